@@ -0,0 +1,348 @@
+//! Settings that don't fit well in flags, read from `$SPROMPT_CONFIG` if set, otherwise from
+//! `$XDG_CONFIG_HOME/sprompt/config.toml` (defaulting to `~/.config/sprompt/config.toml`).
+//!
+//! ```toml
+//! [[markers]]
+//! path = "~/work"
+//! symbol = "🚧"
+//!
+//! [priorities]
+//! timing = 25
+//!
+//! [colors]
+//! path = "#88c0d0"
+//!
+//! [exit_codes]
+//! 130 = { symbol = "^C", color = "yellow" }
+//! "126-127" = { color = "magenta" }
+//! ```
+
+use crate::theme::{parse_color, Theme, ThemeColor};
+#[cfg(test)]
+use crate::Color;
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// `$XDG_CONFIG_HOME/sprompt/config.toml`, defaulting to `~/.config/sprompt/config.toml`.
+fn path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME").filter(|x| !x.is_empty()) {
+        Some(x) => PathBuf::from(x),
+        None => {
+            let mut home = PathBuf::from(env::var_os("HOME")?);
+            home.push(".config");
+            home
+        }
+    };
+    Some(base.join("sprompt/config.toml"))
+}
+
+/// Replace a leading `~` with `$HOME`.
+pub fn expand_tilde(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), env::var_os("HOME")) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            PathBuf::from(home).join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+/// How the separator looks after a command exiting with one of `codes`.
+#[derive(Debug, PartialEq)]
+pub struct ExitCodeStyle {
+    pub codes: RangeInclusive<i32>,
+    /// Replaces the separator, if set.
+    pub symbol: Option<String>,
+    /// Replaces the usual success or error color, if set.
+    pub color: Option<ThemeColor>,
+}
+
+/// Parse an exit code like `130` or a range like `126-127`.
+fn parse_exit_codes(s: &str) -> Option<RangeInclusive<i32>> {
+    let (start, end) = match s.get(1..).and_then(|x| x.split_once('-')) {
+        // Skip the first char so that a negative code isn't taken for a range.
+        Some((start, end)) => (format!("{}{}", &s[..1], start), end),
+        None => (s.into(), s),
+    };
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    if start <= end {
+        Some(start..=end)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    /// Directories tagged with a symbol shown anywhere under them, in the order they appear in
+    /// the file.
+    pub markers: Vec<(PathBuf, String)>,
+    /// Priorities of the segments by name, overriding the defaults. When the prompt doesn't fit
+    /// in `--max-width` the segments with the lowest priority are dropped first, 255 means never.
+    pub priorities: Vec<(String, u8)>,
+    /// Colors overriding the ones of the theme, by role, see `Theme::ROLES`.
+    pub colors: Vec<(String, ThemeColor)>,
+    /// Separators for specific exit codes, the zero/non-zero colors apply to the others.
+    pub exit_codes: Vec<ExitCodeStyle>,
+}
+
+impl Config {
+    /// The config in `$SPROMPT_CONFIG`, or else in the default location, see `load_from`.
+    pub fn load() -> Self {
+        Self::load_from(
+            env::var_os("SPROMPT_CONFIG")
+                .filter(|x| !x.is_empty())
+                .map(PathBuf::from),
+        )
+    }
+
+    /// The config in `explicit`, or else in the default location. A missing file in the default
+    /// location is the same as an empty one, while one named explicitly has to be there.
+    /// Errors are printed to stderr and the file ignored, a broken config shouldn't break the
+    /// prompt.
+    pub fn load_from(explicit: Option<PathBuf>) -> Self {
+        let (path, required) = match explicit {
+            Some(x) => (x, true),
+            None => match path() {
+                Some(x) => (x, false),
+                None => return Self::default(),
+            },
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(e) => {
+                if required {
+                    eprintln!("sprompt: SPROMPT_CONFIG: {}: {}", path.display(), e);
+                }
+                return Self::default();
+            }
+        };
+        Self::parse(&contents).unwrap_or_else(|e| {
+            eprintln!("sprompt: {}: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let value = contents.parse::<Value>().map_err(|e| e.to_string())?;
+        let mut config = Self::default();
+        if let Some(markers) = value.get("markers") {
+            let markers = markers
+                .as_array()
+                .ok_or("`markers` must be an array of tables")?;
+            for marker in markers {
+                let field = |name: &str| {
+                    marker
+                        .get(name)
+                        .and_then(|x| x.as_str())
+                        .ok_or(format!("every marker needs a `{}` string", name))
+                };
+                config
+                    .markers
+                    .push((expand_tilde(field("path")?), field("symbol")?.into()));
+            }
+        }
+        if let Some(priorities) = value.get("priorities") {
+            let priorities = priorities
+                .as_table()
+                .ok_or("`priorities` must be a table")?;
+            for (name, priority) in priorities {
+                let priority = priority
+                    .as_integer()
+                    .and_then(|x| u8::try_from(x).ok())
+                    .ok_or(format!("the priority of `{}` must be in 0..=255", name))?;
+                config.priorities.push((name.clone(), priority));
+            }
+        }
+        if let Some(colors) = value.get("colors") {
+            let colors = colors.as_table().ok_or("`colors` must be a table")?;
+            for (role, color) in colors {
+                if !Theme::ROLES.contains(&role.as_str()) {
+                    return Err(format!("unknown color `{}`", role));
+                }
+                let color = color
+                    .as_str()
+                    .ok_or(format!("the color of `{}` must be a string", role))?;
+                config.colors.push((role.clone(), parse_color(color)?));
+            }
+        }
+        if let Some(exit_codes) = value.get("exit_codes") {
+            let exit_codes = exit_codes
+                .as_table()
+                .ok_or("`exit_codes` must be a table")?;
+            for (codes, style) in exit_codes {
+                let field = |name: &str| match style.get(name) {
+                    Some(x) => x.as_str().map(Some).ok_or(format!(
+                        "the {} of exit code `{}` must be a string",
+                        name, codes
+                    )),
+                    None => Ok(None),
+                };
+                config.exit_codes.push(ExitCodeStyle {
+                    codes: parse_exit_codes(codes)
+                        .ok_or(format!("invalid exit code or range `{}`", codes))?,
+                    symbol: field("symbol")?.map(|x| x.into()),
+                    color: field("color")?.map(parse_color).transpose()?,
+                });
+            }
+        }
+        Ok(config)
+    }
+
+    /// The style for `code`. An exact code wins over a range, and a narrower range over a
+    /// wider one.
+    pub fn exit_code(&self, code: i32) -> Option<&ExitCodeStyle> {
+        self.exit_codes
+            .iter()
+            .filter(|x| x.codes.contains(&code))
+            .min_by_key(|x| i64::from(*x.codes.end()) - i64::from(*x.codes.start()))
+    }
+
+    /// The priority of the segment `name`, if overridden.
+    pub fn priority(&self, name: &str) -> Option<u8> {
+        self.priorities
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, priority)| *priority)
+    }
+
+    /// Symbol of the first marker whose directory contains `cwd`. Both sides are
+    /// canonicalized so that symlinks and `..` don't get in the way.
+    pub fn marker(&self, cwd: &Path) -> Option<&str> {
+        let cwd = cwd.canonicalize().ok()?;
+        self.markers
+            .iter()
+            .find(|(path, _)| path.canonicalize().is_ok_and(|x| cwd.starts_with(x)))
+            .map(|(_, symbol)| symbol.as_str())
+    }
+}
+
+#[test]
+fn test_config_parse() {
+    assert_eq!(Ok(Config::default()), Config::parse(""));
+    let config = Config::parse(
+        r#"
+        [[markers]]
+        path = "/work"
+        symbol = "W"
+
+        [[markers]]
+        path = "/tmp"
+        symbol = "T"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        vec![
+            (PathBuf::from("/work"), "W".to_string()),
+            (PathBuf::from("/tmp"), "T".to_string())
+        ],
+        config.markers
+    );
+    assert!(Config::parse("markers = 1").is_err());
+    let config = Config::parse("[priorities]\ntiming = 25\nload = 0").unwrap();
+    assert_eq!(Some(25), config.priority("timing"));
+    assert_eq!(Some(0), config.priority("load"));
+    assert_eq!(None, config.priority("path"));
+    assert!(Config::parse("[priorities]\ntiming = 256").is_err());
+    let config = Config::parse("[colors]\npath = \"#ff8800\"\nbranch = \"bright-blue\"").unwrap();
+    assert_eq!(
+        vec![
+            ("branch".to_string(), ThemeColor::bright(Color::Blue)),
+            (
+                "path".to_string(),
+                ThemeColor::normal(Color::Rgb(0xff, 0x88, 0x00))
+            )
+        ],
+        config.colors
+    );
+    assert_eq!(
+        Err("invalid color `#ff88`".into()),
+        Config::parse("[colors]\npath = \"#ff88\"")
+    );
+    assert!(Config::parse("[colors]\nbackground = \"red\"").is_err());
+    assert!(Config::parse("[priorities]\ntiming = \"high\"").is_err());
+    assert!(Config::parse("[[markers]]\npath = \"/work\"").is_err());
+    assert!(Config::parse("[[markers").is_err());
+}
+
+#[test]
+fn test_config_load_from() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sprompt.toml");
+    fs::write(&path, "[priorities]\ntiming = 1").unwrap();
+    let config = Config::load_from(Some(path));
+    assert_eq!(Some(1), config.priority("timing"));
+    let missing = dir.path().join("missing.toml");
+    assert_eq!(Config::default(), Config::load_from(Some(missing)));
+    fs::write(dir.path().join("broken.toml"), "[priorities").unwrap();
+    assert_eq!(
+        Config::default(),
+        Config::load_from(Some(dir.path().join("broken.toml")))
+    );
+}
+
+#[test]
+fn test_config_exit_codes() {
+    let config = Config::parse(
+        r#"
+        [exit_codes]
+        130 = { symbol = "^C", color = "yellow" }
+        "128-165" = { symbol = "⚡" }
+        "1-255" = { color = "bright-red" }
+        "#,
+    )
+    .unwrap();
+    // Exact code.
+    let style = config.exit_code(130).unwrap();
+    assert_eq!(Some("^C"), style.symbol.as_deref());
+    assert_eq!(Some(ThemeColor::normal(Color::Yellow)), style.color);
+    // Range, the narrowest one.
+    let style = config.exit_code(137).unwrap();
+    assert_eq!(128..=165, style.codes);
+    assert_eq!((Some("⚡"), None), (style.symbol.as_deref(), style.color));
+    assert_eq!(1..=255, config.exit_code(1).unwrap().codes);
+    assert_eq!(None, config.exit_code(0));
+    assert_eq!(Some(-1..=-1), parse_exit_codes("-1"));
+    assert_eq!(Some(-2..=3), parse_exit_codes("-2-3"));
+    assert_eq!(None, parse_exit_codes("3-1"));
+    assert_eq!(None, parse_exit_codes("1-"));
+    assert!(Config::parse(
+        "[exit_codes]
+abc = { symbol = \"x\" }"
+    )
+    .is_err());
+    assert!(Config::parse(
+        "[exit_codes]
+1 = { color = \"purple\" }"
+    )
+    .is_err());
+    assert!(Config::parse(
+        "[exit_codes]
+1 = { symbol = 1 }"
+    )
+    .is_err());
+}
+
+#[test]
+fn test_config_marker() {
+    let dir = tempfile::tempdir().unwrap();
+    let work = dir.path().join("work");
+    fs::create_dir_all(work.join("a/b")).unwrap();
+    fs::create_dir_all(dir.path().join("workshop")).unwrap();
+    let config = Config {
+        markers: vec![
+            (work.clone(), "W".into()),
+            (dir.path().to_owned(), "T".into()),
+        ],
+        ..Default::default()
+    };
+    assert_eq!(Some("W"), config.marker(&work.join("a/b")));
+    assert_eq!(Some("W"), config.marker(&work.join("a/../a")));
+    // Prefix of the path but not of its components.
+    assert_eq!(Some("T"), config.marker(&dir.path().join("workshop")));
+    assert_eq!(None, config.marker(Path::new("/")));
+}
@@ -0,0 +1,96 @@
+//! Translations of the words shown in the prompt.
+
+use std::env;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Message {
+    Root,
+    In,
+    On,
+    Took,
+}
+
+type Bundle = &'static [(Message, &'static str)];
+
+const EN: Bundle = &[
+    (Message::Root, "root"),
+    (Message::In, "in"),
+    (Message::On, "on"),
+    (Message::Took, "took"),
+];
+const DE: Bundle = &[
+    (Message::In, "in"),
+    (Message::On, "auf"),
+    (Message::Took, "dauerte"),
+];
+const ES: Bundle = &[
+    (Message::In, "en"),
+    (Message::On, "en"),
+    (Message::Took, "tardó"),
+];
+const FR: Bundle = &[
+    (Message::In, "dans"),
+    (Message::On, "sur"),
+    (Message::Took, "a pris"),
+];
+const IT: Bundle = &[
+    (Message::In, "in"),
+    (Message::On, "su"),
+    (Message::Took, "durata"),
+];
+
+const BUNDLES: [(&str, Bundle); 5] = [("en", EN), ("de", DE), ("es", ES), ("fr", FR), ("it", IT)];
+
+/// `message` in the language `lang` (e.g. `it`), in english if the language or the message
+/// isn't translated.
+pub fn tr(lang: &str, message: Message) -> &'static str {
+    let find = |bundle: Bundle| {
+        bundle
+            .iter()
+            .find(|(x, _)| *x == message)
+            .map(|(_, text)| *text)
+    };
+    BUNDLES
+        .iter()
+        .find(|(code, _)| *code == lang)
+        .and_then(|(_, bundle)| find(bundle))
+        .or_else(|| find(EN))
+        .unwrap_or_default()
+}
+
+/// Language code of the current locale, e.g. `it` for `it_IT.UTF-8`. Defaults to `en`.
+pub fn lang_from_env() -> String {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|x| env::var(x).ok())
+        .find(|x| !x.is_empty())
+        .map(|x| lang_code(&x).into())
+        .unwrap_or_else(|| "en".into())
+}
+
+fn lang_code(locale: &str) -> &str {
+    match locale.split(['_', '.', '@']).next() {
+        Some("C") | Some("POSIX") | Some("") | None => "en",
+        Some(code) => code,
+    }
+}
+
+#[test]
+fn test_tr() {
+    assert_eq!("took", tr("en", Message::Took));
+    assert_eq!("dauerte", tr("de", Message::Took));
+    assert_eq!("a pris", tr("fr", Message::Took));
+    // Missing message.
+    assert_eq!("root", tr("it", Message::Root));
+    // Missing language.
+    assert_eq!("took", tr("xx", Message::Took));
+}
+
+#[test]
+fn test_lang_code() {
+    assert_eq!("it", lang_code("it_IT.UTF-8"));
+    assert_eq!("de", lang_code("de"));
+    assert_eq!("sr", lang_code("sr@latin"));
+    assert_eq!("en", lang_code("C.UTF-8"));
+    assert_eq!("en", lang_code("POSIX"));
+}
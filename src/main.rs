@@ -1,18 +1,69 @@
 #![allow(dead_code)]
 
+mod config;
+mod i18n;
+mod state;
+#[cfg(test)]
+mod testutil;
+mod theme;
+mod toolchain;
+
 use clap::{
-    crate_authors, crate_description, crate_name, crate_version, App, AppSettings, Arg, SubCommand,
+    crate_authors, crate_description, crate_name, crate_version, App, AppSettings, Arg, ArgMatches,
+    SubCommand,
+};
+use config::Config;
+use git2::{
+    Branch, DiffOptions, ErrorCode, Repository, RepositoryState, Status, StatusOptions,
+    SubmoduleIgnore, SubmoduleStatus,
 };
-use git2::{ErrorCode, Repository};
+use i18n::Message;
+use state::State;
+use std::borrow::Cow;
 use std::convert::TryFrom;
 use std::env;
-use std::fmt::Write;
+use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Write};
 use std::fs;
-use std::time::Duration;
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal, Write as _};
+use std::path::{Component, Path, PathBuf, MAIN_SEPARATOR, MAIN_SEPARATOR_STR};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use theme::{Theme, ThemeColor};
+use toolchain::Toolchain;
+use unicode_width::UnicodeWidthChar;
 
 const MIN_CMD_EXEC_TIME: Duration = Duration::from_secs(2);
+/// The load segment is shown when the 1-minute load average per CPU is above this.
+const HIGH_LOAD_PER_CPU: f64 = 1.0;
+/// Walking the history stops after this many commits, see `Git::commit_count`.
+const MAX_COMMIT_COUNT: usize = 9999;
+/// `--todo-count` stops scanning the diff after this many added lines.
+const MAX_TODO_LINES: usize = 10_000;
+/// Default `--diskspace` threshold, in percent.
+const LOW_DISK_SPACE_PERCENT: f64 = 10.0;
+/// The limits segment is shown when the open files are at least this percentage of the limit.
+const HIGH_OPEN_FILES_PERCENT: u64 = 80;
+/// Consecutive successful commands needed for `--mood` to show a happy face.
+const HAPPY_STREAK: i64 = 5;
+
+fn directory_validator(s: String) -> Result<(), String> {
+    if Path::new(&s).is_dir() {
+        Ok(())
+    } else {
+        Err("The argument must be an existing directory".into())
+    }
+}
+
+fn percent_validator(s: String) -> Result<(), String> {
+    match s.parse::<f64>() {
+        Ok(x) if (0.0..=100.0).contains(&x) => Ok(()),
+        _ => Err("The argument must be a percentage between 0 and 100".into()),
+    }
+}
 
-fn elapsed_seconds_validator(s: String) -> Result<(), String> {
+fn usize_validator(s: String) -> Result<(), String> {
     if s.parse::<usize>().is_err() {
         Err("The argument must be a valid positive integer".into())
     } else {
@@ -20,6 +71,105 @@ fn elapsed_seconds_validator(s: String) -> Result<(), String> {
     }
 }
 
+/// Like `usize_validator`, but an empty value counts as not given, e.g. `--columns "$COLUMNS"`
+/// before the shell sets `COLUMNS`.
+fn usize_or_empty_validator(s: String) -> Result<(), String> {
+    if s.is_empty() {
+        Ok(())
+    } else {
+        usize_validator(s)
+    }
+}
+
+/// Parse a non-negative integer, clamping values that don't fit in a `u64` instead of failing.
+fn parse_clamped_u64(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    Some(s.parse().unwrap_or(u64::MAX))
+}
+
+#[test]
+fn test_parse_clamped_u64() {
+    assert_eq!(Some(42), parse_clamped_u64("42"));
+    assert_eq!(Some(5_000_000_000), parse_clamped_u64("5000000000"));
+    assert_eq!(Some(u64::MAX), parse_clamped_u64("99999999999999999999999"));
+    assert_eq!(None, parse_clamped_u64(""));
+    assert_eq!(None, parse_clamped_u64("-1"));
+    assert_eq!(None, parse_clamped_u64("1s"));
+}
+
+/// Time passed from `start_ns`, nanoseconds since the Unix epoch, to `now`. Zero if `start_ns`
+/// is in the future, e.g. because the clock changed.
+fn elapsed_since(start_ns: u64, now: SystemTime) -> Duration {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.checked_sub(Duration::from_nanos(start_ns))
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_elapsed_since() {
+    let now = UNIX_EPOCH + Duration::from_secs(100);
+    assert_eq!(
+        Duration::from_millis(2500),
+        elapsed_since(97_500_000_000, now)
+    );
+    assert_eq!(Duration::from_secs(0), elapsed_since(200_000_000_000, now));
+}
+
+fn u64_validator(s: String) -> Result<(), String> {
+    match parse_clamped_u64(&s) {
+        Some(_) => Ok(()),
+        None => Err("The argument must be a valid positive integer".into()),
+    }
+}
+
+fn non_zero_usize_validator(s: String) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(()),
+        _ => Err("The argument must be a valid integer greater than 0".into()),
+    }
+}
+
+fn shell_identifier_validator(s: String) -> Result<(), String> {
+    let mut chars = s.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|x| x.is_ascii_alphabetic() || x == '_')
+        && chars.all(|x| x.is_ascii_alphanumeric() || x == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err("The argument must be a valid shell identifier ([A-Za-z_][A-Za-z0-9_]*)".into())
+    }
+}
+
+fn branch_symbol_validator(s: String) -> Result<(), String> {
+    match s.split_once('=') {
+        Some((prefix, _)) if !prefix.is_empty() => Ok(()),
+        _ => Err("The argument must be in the form PREFIX=SYMBOL".into()),
+    }
+}
+
+fn time_icon_bands_validator(s: String) -> Result<(), String> {
+    match parse_time_icon_bands(&s) {
+        Some(_) => Ok(()),
+        None => {
+            Err("The argument must be in the form FAST,SLOW, in seconds, with FAST <= SLOW".into())
+        }
+    }
+}
+
+#[test]
+fn test_shell_identifier_validator() {
+    assert!(shell_identifier_validator("my_prompt".into()).is_ok());
+    assert!(shell_identifier_validator("_p2".into()).is_ok());
+    assert!(shell_identifier_validator("".into()).is_err());
+    assert!(shell_identifier_validator("2p".into()).is_err());
+    assert!(shell_identifier_validator("my-prompt".into()).is_err());
+    assert!(shell_identifier_validator("a;b".into()).is_err());
+}
+
 #[inline]
 fn humanize_duration(dur: &Duration) -> String {
     let secs = dur.as_secs();
@@ -38,13 +188,13 @@ fn humanize_duration(dur: &Duration) -> String {
         .trim_end()
         .into();
     }
-    return format!(
+    format!(
         "{}h {}",
         (secs as f32 / 3600.0).trunc() as usize,
         humanize_duration(&Duration::from_secs(secs % 3600)),
     )
     .trim_end()
-    .into();
+    .into()
 }
 
 #[test]
@@ -69,278 +219,5879 @@ fn test_humanize_duration() {
     );
 }
 
-fn main() {
-    let matches = App::new(crate_name!())
-        .version(crate_version!())
-        .author(crate_authors!())
-        .about(crate_description!())
-        .settings(&[AppSettings::SubcommandRequired])
-        .global_settings(&[AppSettings::ColorAuto, AppSettings::ColoredHelp])
-        .subcommand(
-            SubCommand::with_name("prompt")
-                .about("Output the prompt string")
-                .arg(
-                    Arg::with_name("exit_code")
-                        .long("exit-code")
-                        .short("e")
-                        .takes_value(true)
-                        .help("Last command exit code")
-                        .required(true),
-                )
-                .arg(
-                    Arg::with_name("shell")
-                        .long("shell")
-                        .short("s")
-                        .takes_value(true)
-                        .help("The shell where the prompt will be shown")
-                        .required(true)
-                        .possible_values(&Shell::SUPPORTED),
-                )
-                .arg(
-                    Arg::with_name("elapsed_seconds")
-                        .long("elapsed-seconds")
-                        .takes_value(true)
-                        .help("Last command's execution time in seconds")
-                        .required(true)
-                        .validator(elapsed_seconds_validator),
-                )
-                .arg(
-                    Arg::with_name("unicode")
-                        .long("unicode")
-                        .short("u")
-                        .help("Use unicode symbols"),
-                )
-                .arg(
-                    Arg::with_name("short_path")
-                        .long("short-path")
-                        .short("p")
-                        .help("Show the current path in a reduced form"),
-                ),
-        )
-        .subcommand(
-            SubCommand::with_name("init")
-                .about("Output code to be evaluated by the shell to init the prompt")
-                .arg(
-                    Arg::with_name("shell")
-                        .long("shell")
-                        .short("s")
-                        .takes_value(true)
-                        .help("The shell for which to output the init code")
-                        .required(true)
-                        .possible_values(&Shell::SUPPORTED),
-                )
-                .arg(
-                    Arg::with_name("unicode")
-                        .long("unicode")
-                        .short("u")
-                        .help("Use unicode symbols"),
-                )
-                .arg(
-                    Arg::with_name("short_path")
-                        .long("short-path")
-                        .short("p")
-                        .help("Show the current path in a reduced form"),
-                ),
-        )
-        .get_matches();
+/// Compact relative time like `3d ago`, unlike `humanize_duration` only the largest unit is
+/// kept since the exact time rarely matters. `suffix` (e.g. `ago`) is appended after a space,
+/// unless empty.
+fn humanize_relative(dur: &Duration, suffix: &str) -> String {
+    const UNITS: [(u64, &str); 6] = [
+        (60 * 60 * 24 * 365, "y"),
+        (60 * 60 * 24 * 7, "w"),
+        (60 * 60 * 24, "d"),
+        (60 * 60, "h"),
+        (60, "m"),
+        (1, "s"),
+    ];
+    let secs = dur.as_secs();
+    match UNITS.iter().find(|(unit, _)| secs >= *unit) {
+        Some((unit, name)) if suffix.is_empty() => format!("{}{}", secs / unit, name),
+        Some((unit, name)) => format!("{}{} {}", secs / unit, name, suffix),
+        None => "now".into(),
+    }
+}
 
-    match matches.subcommand() {
-        ("prompt", Some(matches)) => {
-            let non_zero_exit_status = matches.value_of("exit_code").unwrap() != "0";
-            let ref shell = Shell::try_from(matches.value_of("shell").unwrap()).unwrap();
+#[test]
+fn test_humanize_relative() {
+    let relative = |secs| humanize_relative(&Duration::from_secs(secs), "ago");
+    assert_eq!("now", humanize_relative(&Duration::from_millis(500), "ago"));
+    assert_eq!("now", relative(0));
+    assert_eq!("1s ago", relative(1));
+    assert_eq!("59s ago", relative(59));
+    assert_eq!("1m ago", relative(60));
+    assert_eq!("1m ago", relative(119));
+    assert_eq!("23h ago", relative(60 * 60 * 24 - 1));
+    assert_eq!("1d ago", relative(60 * 60 * 24));
+    assert_eq!("3d ago", relative(60 * 60 * 24 * 3 + 60 * 60 * 4 + 5 * 60));
+    assert_eq!("2w ago", relative(60 * 60 * 24 * 14));
+    assert_eq!("1y ago", relative(60 * 60 * 24 * 400));
+    assert_eq!(
+        "3d",
+        humanize_relative(&Duration::from_secs(60 * 60 * 24 * 3), "")
+    );
+}
 
-            let use_unicode = matches.is_present("unicode");
-            let branch_symbol = if use_unicode {
-                " "
-            } else {
-                Default::default()
-            };
-            let separator_symbol = if use_unicode { "❯" } else { "::" };
+/// Size in kilobytes like `120MB`, with one decimal from 10GB down to keep it short.
+fn humanize_kb(kb: u64) -> String {
+    const MB: u64 = 1024;
+    const GB: u64 = 1024 * 1024;
+    if kb < MB {
+        format!("{}KB", kb)
+    } else if kb < GB {
+        format!("{}MB", kb / MB)
+    } else if kb < 10 * GB {
+        format!("{:.1}GB", kb as f64 / GB as f64)
+    } else {
+        format!("{}GB", kb / GB)
+    }
+}
 
-            let git = Git::new();
+#[test]
+fn test_humanize_kb() {
+    assert_eq!("0KB", humanize_kb(0));
+    assert_eq!("1023KB", humanize_kb(1023));
+    assert_eq!("120MB", humanize_kb(120 * 1024 + 500));
+    assert_eq!("1.5GB", humanize_kb(1024 * 1024 * 3 / 2));
+    assert_eq!("12GB", humanize_kb(12 * 1024 * 1024));
+}
 
-            let use_short_path = matches.is_present("short_path");
-            let path = get_current_path(if use_short_path {
-                Some(git.as_ref().and_then(|x| x.toplevel()))
-            } else {
-                None
-            })
-            .unwrap_or("??".into());
-
-            // TODO(agnipau): Windows support.
-            let is_root = unsafe { libc::getuid() } == 0;
-
-            // parse can't fail, we checked this using clap.
-            let elapsed: usize = matches
-                .value_of("elapsed_seconds")
-                .unwrap()
-                .parse()
-                .unwrap();
-            let elapsed = Duration::from_secs(elapsed as u64);
-
-            let mut s = String::new();
-            if is_root {
-                let _ = write!(
-                    &mut s,
-                    "{}{}root{} in ",
-                    Attribute::Bold.to_str(shell),
-                    Color::Red.to_str(false, shell),
-                    Attribute::Reset.to_str(shell)
-                );
+/// Default limits of the fast and slow commands for `--time-icon`.
+const TIME_ICON_BANDS: (Duration, Duration) = (Duration::from_secs(2), Duration::from_secs(30));
+
+/// Parse `--time-icon-bands`, e.g. `2,30`.
+fn parse_time_icon_bands(s: &str) -> Option<(Duration, Duration)> {
+    let (fast, slow) = s.split_once(',')?;
+    let (fast, slow) = (fast.parse().ok()?, slow.parse().ok()?);
+    if fast <= slow {
+        Some((Duration::from_secs(fast), Duration::from_secs(slow)))
+    } else {
+        None
+    }
+}
+
+/// Glyph telling how slow a command was at a glance: fast below `bands.0`, slow from
+/// `bands.1` on and in between otherwise.
+fn time_icon(elapsed: Duration, bands: (Duration, Duration), unicode: bool) -> &'static str {
+    match (elapsed, unicode) {
+        (x, true) if x < bands.0 => "⚡",
+        (x, false) if x < bands.0 => "!",
+        (x, true) if x < bands.1 => "⏱",
+        (x, false) if x < bands.1 => "~",
+        (_, true) => "🐌",
+        (_, false) => "#",
+    }
+}
+
+#[test]
+fn test_time_icon() {
+    let icon = |secs, unicode| time_icon(Duration::from_secs(secs), TIME_ICON_BANDS, unicode);
+    assert_eq!("⚡", icon(1, true));
+    assert_eq!("⏱", icon(2, true));
+    assert_eq!("⏱", icon(29, true));
+    assert_eq!("🐌", icon(30, true));
+    assert_eq!(
+        ("!", "~", "#"),
+        (icon(1, false), icon(5, false), icon(60, false))
+    );
+    let bands = parse_time_icon_bands("5,10").unwrap();
+    assert_eq!("⚡", time_icon(Duration::from_secs(3), bands, true));
+    assert_eq!("🐌", time_icon(Duration::from_secs(10), bands, true));
+    assert_eq!(None, parse_time_icon_bands("10,5"));
+    assert_eq!(None, parse_time_icon_bands("5"));
+}
+
+/// How the elapsed time is shown, see `--duration-format`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DurationFormat {
+    /// All the units, like `2m 5s`.
+    Auto,
+    /// Total seconds, like `125s`.
+    Seconds,
+    /// Only the largest unit, like `2m`.
+    Compact,
+}
+
+impl DurationFormat {
+    const SUPPORTED: [&'static str; 3] = ["auto", "seconds", "compact"];
+
+    fn format(self, dur: &Duration) -> String {
+        match self {
+            Self::Auto => humanize_duration(dur),
+            Self::Seconds => format!("{}s", dur.as_secs()),
+            Self::Compact => humanize_relative(dur, ""),
+        }
+    }
+}
+
+impl TryFrom<&str> for DurationFormat {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "seconds" => Ok(Self::Seconds),
+            "compact" => Ok(Self::Compact),
+            _ => Err(()),
+        }
+    }
+}
+
+#[test]
+fn test_duration_format() {
+    let dur = Duration::from_secs(60 * 60 + 2 * 60 + 5);
+    let format = |x| DurationFormat::try_from(x).unwrap().format(&dur);
+    assert_eq!("1h 2m 5s", format("auto"));
+    assert_eq!("3725s", format("seconds"));
+    assert_eq!("1h", format("compact"));
+    assert!(DurationFormat::try_from("hours").is_err());
+}
+
+/// Number of terminal columns `s` takes once printed, escape sequences and zsh's `%{ %}`
+/// zero-width markers are ignored.
+fn display_width(s: &str) -> usize {
+    let mut width = 0;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{001b}' => {
+                // CSI sequences end with a character in the `@`..=`~` range, OSC ones with BEL
+                // or ESC \.
+                if chars.next_if_eq(&'[').is_some() {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                } else if chars.next_if_eq(&']').is_some() {
+                    while let Some(c) = chars.next() {
+                        if c == '\u{0007}' || (c == '\u{001b}' && chars.next_if_eq(&'\\').is_some())
+                        {
+                            break;
+                        }
+                    }
+                }
             }
-            let _ = write!(
-                &mut s,
-                "{}{}{} ",
-                Attribute::Bold.to_str(shell),
-                Color::Cyan.to_str(false, shell),
-                path
-            );
-            if let Some(branch) = git.and_then(|x| x.branch()) {
-                let _ = write!(
-                    &mut s,
-                    "{}on {}{}{}{} ",
-                    Attribute::Reset.to_str(shell),
-                    Attribute::Bold.to_str(shell),
-                    Color::Magenta.to_str(false, shell),
-                    branch_symbol,
-                    branch
-                );
-                // TODO(agnipau): Checking for git dirty state in a decently performant way in big repos
-                // (like UnrealEngine) is quite difficult.
+            '%' if matches!(chars.peek(), Some('{') | Some('}')) => {
+                chars.next();
             }
-            if elapsed >= MIN_CMD_EXEC_TIME {
-                let _ = write!(
-                    &mut s,
-                    "{}took {} ",
-                    Color::Yellow.to_str(false, shell),
-                    humanize_duration(&elapsed),
-                );
+            c => width += c.width().unwrap_or(0),
+        }
+    }
+    width
+}
+
+#[test]
+fn test_display_width() {
+    assert_eq!(0, display_width(""));
+    assert_eq!(3, display_width("abc"));
+    assert_eq!(2, display_width("\u{001b}[1m\u{001b}[36;1m~/\u{001b}[0m"));
+    assert_eq!(1, display_width("%{\u{001b}[32m%}❯%{\u{001b}[0m%}"));
+    assert_eq!(4, display_width("日本"));
+    assert_eq!(
+        2,
+        display_width("\u{001b}]8;;file:///a\u{001b}\\~/\u{001b}]8;;\u{001b}\\")
+    );
+}
+
+/// Remove the escape sequences (colors, attributes and hyperlinks) from `s`, together with
+/// the zsh `%{ %}` markers left empty.
+fn strip_escapes(s: &str) -> String {
+    let mut stripped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\u{001b}' {
+            stripped.push(c);
+        } else if chars.next_if_eq(&'[').is_some() {
+            // Same rules as `display_width`.
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
             }
-            let _ = write!(
-                &mut s,
-                "{}{}{}",
-                if non_zero_exit_status {
-                    Color::Red.to_str(false, shell)
-                } else {
-                    Color::Green.to_str(false, shell)
-                },
-                separator_symbol,
-                Attribute::Reset.to_str(shell),
-            );
-            if *shell != Shell::Bash {
-                let _ = write!(&mut s, " ");
+        } else if chars.next_if_eq(&']').is_some() {
+            while let Some(c) = chars.next() {
+                if c == '\u{0007}' || (c == '\u{001b}' && chars.next_if_eq(&'\\').is_some()) {
+                    break;
+                }
+            }
+        }
+    }
+    stripped.replace("%{%}", "")
+}
+
+#[test]
+fn test_strip_escapes() {
+    assert_eq!("", strip_escapes(""));
+    assert_eq!(
+        "~/a ❯",
+        strip_escapes("\u{001b}[1m\u{001b}[36;1m~/a \u{001b}[32m❯\u{001b}[0m")
+    );
+    assert_eq!("❯", strip_escapes("%{\u{001b}[32m%}❯%{\u{001b}[0m%}"));
+    assert_eq!(
+        "~/",
+        strip_escapes("\u{001b}]8;;file:///a\u{001b}\\~/\u{001b}]8;;\u{001b}\\")
+    );
+}
+
+/// Surround the escape sequences in `s` with `\001` and `\002`, which tell readline that they
+/// take no space. Unlike `\[` and `\]` they also work in the value of a variable.
+fn mark_escapes(s: &str) -> String {
+    let mut marked = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\u{001b}' {
+            marked.push(c);
+            continue;
+        }
+        marked.push_str("\u{0001}\u{001b}");
+        // Same rules as `strip_escapes`.
+        if let Some(c) = chars.next_if_eq(&'[') {
+            marked.push(c);
+            for c in chars.by_ref() {
+                marked.push(c);
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
             }
+        } else if let Some(c) = chars.next_if_eq(&']') {
+            marked.push(c);
+            while let Some(c) = chars.next() {
+                marked.push(c);
+                if c == '\u{0007}' {
+                    break;
+                }
+                if c == '\u{001b}' {
+                    if let Some(c) = chars.next_if_eq(&'\\') {
+                        marked.push(c);
+                        break;
+                    }
+                }
+            }
+        }
+        marked.push('\u{0002}');
+    }
+    marked
+}
+
+#[test]
+fn test_mark_escapes() {
+    assert_eq!("~/a", mark_escapes("~/a"));
+    assert_eq!(
+        "\u{1}\u{1b}[1m\u{2}\u{1}\u{1b}[36;1m\u{2}~/a \u{1}\u{1b}[0m\u{2}",
+        mark_escapes("\u{1b}[1m\u{1b}[36;1m~/a \u{1b}[0m")
+    );
+    assert_eq!(
+        "\u{1}\u{1b}]8;;file:///a\u{1b}\\\u{2}~/",
+        mark_escapes("\u{1b}]8;;file:///a\u{1b}\\~/")
+    );
+}
+
+/// Wrap `text` in an OSC 8 hyperlink to the local directory `path`. Terminals that don't
+/// support hyperlinks ignore the escapes.
+fn hyperlink(text: &str, path: &str, shell: &Shell) -> String {
+    let mut url = String::from("file://");
+    url.push_str(&hostname().unwrap_or_default());
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            url.push(b as char);
+        } else {
+            let _ = write!(&mut url, "%{:02X}", b);
+        }
+    }
+    let (open, close) = match shell {
+        // `%` is special in zsh prompts.
+        Shell::Zsh => (
+            format!("%{{\u{001b}]8;;{}\u{001b}\\%}}", url.replace('%', "%%")),
+            "%{\u{001b}]8;;\u{001b}\\%}",
+        ),
+        Shell::Bash => (
+            format!("\u{001b}]8;;{}\u{001b}\\", url),
+            "\u{001b}]8;;\u{001b}\\",
+        ),
+    };
+    format!("{}{}{}", open, text, close)
+}
+
+#[test]
+fn test_hyperlink() {
+    let host = hostname().unwrap_or_default();
+    assert_eq!(
+        format!(
+            "\u{001b}]8;;file://{}/a%20b\u{001b}\\~/a b\u{001b}]8;;\u{001b}\\",
+            host
+        ),
+        hyperlink("~/a b", "/a b", &Shell::Bash)
+    );
+    assert_eq!(
+        format!(
+            "%{{\u{001b}]8;;file://{}/a%%20b\u{001b}\\%}}x%{{\u{001b}]8;;\u{001b}\\%}}",
+            host
+        ),
+        hyperlink("x", "/a b", &Shell::Zsh)
+    );
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|x| *x == 0)?;
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+/// Escape `s` so that it can be put between double quotes in a JSON string.
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(&mut escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_json_escape() {
+    assert_eq!("abc", json_escape("abc"));
+    assert_eq!(r#"a\"b\\c\n\u0009"#, json_escape("a\"b\\c\n\t"));
+}
+
+/// Measures how long each phase of the prompt generation takes. Does nothing unless a file to
+/// write the results to was given.
+struct Profiler {
+    path: Option<PathBuf>,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Profiler {
+    fn new(path: Option<&str>) -> Self {
+        Self {
+            path: path.map(PathBuf::from),
+            phases: Vec::new(),
+        }
+    }
+
+    fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        if self.path.is_none() {
+            return f();
+        }
+        let start = Instant::now();
+        let result = f();
+        self.phases.push((phase, start.elapsed()));
+        result
+    }
+
+    /// Append a JSON line with the cwd, a timestamp and the duration of each phase in
+    /// microseconds. Errors are ignored, profiling must never break the prompt.
+    fn write(&self) {
+        let path = match &self.path {
+            Some(x) => x,
+            None => return,
+        };
+        let cwd = env::current_dir()
+            .ok()
+            .and_then(|x| x.to_str().map(json_escape))
+            .unwrap_or_default();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis())
+            .unwrap_or(0);
+        let mut line = format!(
+            r#"{{"cwd":"{}","timestamp_ms":{},"phases_us":{{"#,
+            cwd, timestamp
+        );
+        for (i, (phase, dur)) in self.phases.iter().enumerate() {
+            if i != 0 {
+                line.push(',');
+            }
+            let _ = write!(&mut line, r#""{}":{}"#, phase, dur.as_micros());
+        }
+        line.push_str("}}\n");
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SegmentKind {
+    Root,
+    Path,
+    Marker,
+    Branch,
+    DefaultBranch,
+    Operation,
+    Sparse,
+    Hooks,
+    Worktrees,
+    Local,
+    Signed,
+    GitIdentity,
+    Status,
+    DetailedStatus,
+    TodoCount,
+    Submodules,
+    Divergence,
+    Incoming,
+    CommitCount,
+    HeadAge,
+    Toolchain,
+    Nix,
+    Conda,
+    Direnv,
+    SshAgent,
+    EnvFlags,
+    Timing,
+    Load,
+    Limits,
+    DiskSpace,
+    Updates,
+    Dirs,
+    Script,
+    SelfTime,
+    Readonly,
+    Mood,
+    Signal,
+    StickyError,
+    Separator,
+}
+
+impl SegmentKind {
+    /// When the prompt doesn't fit, segments are dropped starting from the lowest priority.
+    /// Segments with `u8::MAX` priority are never dropped.
+    const fn priority(&self) -> u8 {
+        match self {
+            Self::Timing
+            | Self::HeadAge
+            | Self::Load
+            | Self::Limits
+            | Self::DiskSpace
+            | Self::Mood
+            | Self::Updates
+            | Self::Dirs
+            | Self::Script
+            | Self::SelfTime => 10,
+            Self::Marker
+            | Self::Toolchain
+            | Self::Nix
+            | Self::Conda
+            | Self::Direnv
+            | Self::SshAgent
+            | Self::EnvFlags
+            | Self::CommitCount => 12,
+            Self::DefaultBranch
+            | Self::Sparse
+            | Self::Hooks
+            | Self::Worktrees
+            | Self::Local
+            | Self::Signed
+            | Self::GitIdentity
+            | Self::Status
+            | Self::DetailedStatus
+            | Self::TodoCount
+            | Self::Submodules
+            | Self::Divergence
+            | Self::Incoming => 15,
+            Self::Branch => 20,
+            Self::Operation | Self::Signal | Self::StickyError | Self::Readonly => 30,
+            Self::Root | Self::Path | Self::Separator => u8::MAX,
+        }
+    }
+
+    /// All the kinds, in the order they appear in the prompt.
+    const ALL: [Self; 39] = [
+        Self::Root,
+        Self::Path,
+        Self::Marker,
+        Self::Branch,
+        Self::DefaultBranch,
+        Self::Operation,
+        Self::Sparse,
+        Self::Hooks,
+        Self::Worktrees,
+        Self::Local,
+        Self::Signed,
+        Self::GitIdentity,
+        Self::Status,
+        Self::DetailedStatus,
+        Self::TodoCount,
+        Self::Divergence,
+        Self::Incoming,
+        Self::Submodules,
+        Self::CommitCount,
+        Self::HeadAge,
+        Self::Toolchain,
+        Self::Nix,
+        Self::Conda,
+        Self::Direnv,
+        Self::SshAgent,
+        Self::EnvFlags,
+        Self::Timing,
+        Self::StickyError,
+        Self::Mood,
+        Self::Signal,
+        Self::Readonly,
+        Self::Load,
+        Self::Limits,
+        Self::DiskSpace,
+        Self::Updates,
+        Self::Dirs,
+        Self::Script,
+        Self::SelfTime,
+        Self::Separator,
+    ];
+
+    const fn name(&self) -> &str {
+        match self {
+            Self::Root => "root",
+            Self::Path => "path",
+            Self::Marker => "marker",
+            Self::Branch => "branch",
+            Self::DefaultBranch => "default_branch",
+            Self::Operation => "operation",
+            Self::Sparse => "sparse",
+            Self::Hooks => "hooks",
+            Self::Worktrees => "worktrees",
+            Self::Local => "local",
+            Self::Signed => "signed",
+            Self::GitIdentity => "git_identity",
+            Self::Status => "status",
+            Self::DetailedStatus => "detailed_status",
+            Self::TodoCount => "todo_count",
+            Self::Submodules => "submodules",
+            Self::Divergence => "divergence",
+            Self::Incoming => "incoming",
+            Self::CommitCount => "commit_count",
+            Self::HeadAge => "head_age",
+            Self::Toolchain => "toolchain",
+            Self::Nix => "nix",
+            Self::Conda => "conda",
+            Self::Direnv => "direnv",
+            Self::SshAgent => "ssh_agent",
+            Self::EnvFlags => "env_flags",
+            Self::Timing => "timing",
+            Self::Load => "load",
+            Self::Limits => "limits",
+            Self::DiskSpace => "diskspace",
+            Self::Updates => "updates",
+            Self::Dirs => "dirs",
+            Self::Script => "script",
+            Self::SelfTime => "self_time",
+            Self::Readonly => "readonly",
+            Self::Mood => "mood",
+            Self::Signal => "signal",
+            Self::StickyError => "sticky_error",
+            Self::Separator => "separator",
+        }
+    }
+
+    /// Name of the argument enabling the segment, for the ones that are opt-in.
+    const fn arg(&self) -> Option<&str> {
+        match self {
+            Self::DefaultBranch => Some("warn_default_branch"),
+            Self::Operation => Some("operation"),
+            Self::Sparse => Some("sparse"),
+            Self::Hooks => Some("warn_hooks"),
+            Self::Worktrees => Some("worktrees"),
+            Self::Local => Some("show_local"),
+            Self::Signed => Some("signed"),
+            Self::GitIdentity => Some("git_identity"),
+            Self::Status => Some("status_summary"),
+            Self::DetailedStatus => Some("detailed_status"),
+            Self::TodoCount => Some("todo_count"),
+            Self::Divergence => Some("compare_remote"),
+            Self::Incoming => Some("incoming"),
+            Self::Submodules => Some("submodules"),
+            Self::CommitCount => Some("commit_count"),
+            Self::HeadAge => Some("head_age"),
+            Self::Toolchain => Some("toolchains"),
+            Self::Nix => Some("nix"),
+            Self::Conda => Some("conda"),
+            Self::Direnv => Some("direnv"),
+            Self::SshAgent => Some("ssh_agent"),
+            Self::EnvFlags => Some("env_flags"),
+            Self::StickyError => Some("sticky_errors"),
+            Self::Mood => Some("mood"),
+            Self::Signal => Some("show_signals"),
+            Self::Readonly => Some("show_readonly"),
+            Self::Load => Some("load"),
+            Self::Limits => Some("limits"),
+            Self::DiskSpace => Some("diskspace"),
+            Self::Updates => Some("updates"),
+            Self::Dirs => Some("dirs"),
+            Self::Script => Some("non_interactive"),
+            Self::SelfTime => Some("self_time"),
+            Self::Root
+            | Self::Path
+            | Self::Marker
+            | Self::Branch
+            | Self::Timing
+            | Self::Separator => None,
+        }
+    }
+
+    /// The git status segments, which `--git-on-right` moves to the right prompt.
+    const fn on_right(&self) -> bool {
+        matches!(
+            self,
+            Self::Local
+                | Self::Signed
+                | Self::GitIdentity
+                | Self::Status
+                | Self::DetailedStatus
+                | Self::TodoCount
+                | Self::Divergence
+                | Self::Incoming
+                | Self::Submodules
+                | Self::CommitCount
+                | Self::HeadAge
+        )
+    }
+}
+
+/// Which prompt the segments are for, the right one only exists in zsh (`RPROMPT`).
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Left,
+    /// Only the segments for which `SegmentKind::on_right` holds, see `sprompt rprompt`.
+    Right,
+    /// Both prompts, to be told apart with `SegmentKind::on_right`, see `--with-rprompt`.
+    Both,
+}
+
+struct Segment {
+    kind: SegmentKind,
+    text: String,
+    /// `kind.priority()` unless overridden in the config file.
+    priority: u8,
+    /// Narrower variant of `text`, used when dropping segments isn't enough to fit.
+    compact: Option<String>,
+}
+
+impl Segment {
+    fn new(kind: SegmentKind, text: String) -> Self {
+        Self {
+            kind,
+            text,
+            priority: kind.priority(),
+            compact: None,
+        }
+    }
+}
+
+/// A connector word followed by a space, nothing at all if the word is empty.
+fn connector(word: &str) -> String {
+    if word.is_empty() {
+        String::new()
+    } else {
+        format!("{} ", word)
+    }
+}
+
+fn render(segments: Vec<Segment>, trailing_space: bool, colors: bool) -> String {
+    let mut s = segments.into_iter().map(|x| x.text).collect::<String>();
+    if !colors {
+        s = strip_escapes(&s);
+    }
+    if trailing_space {
+        s.push(' ');
+    }
+    s
+}
+
+/// The right prompt, without the trailing space of the segments. Unlike the left one it
+/// doesn't end with the separator, so the colors are reset here.
+fn render_right(segments: Vec<Segment>, shell: &Shell, colors: bool) -> String {
+    let mut s = render(segments, false, colors).trim_end().to_string();
+    if colors && !s.is_empty() {
+        s.push_str(Attribute::Reset.to_str(shell));
+    }
+    s
+}
+
+/// The left and right prompts separated by a NUL byte, see `--with-rprompt`. A NUL can't be
+/// part of a path, an environment variable or a git ref, the config file is the only way one
+/// could sneak in, so it's dropped to be sure.
+fn join_prompts(left: &str, right: &str) -> String {
+    format!("{}\0{}", left.replace('\0', ""), right.replace('\0', ""))
+}
+
+#[test]
+fn test_join_prompts() {
+    assert_eq!("a :: \0+1", join_prompts("a :: ", "+1"));
+    assert_eq!("a :: \0", join_prompts("a :: ", ""));
+    assert_eq!("a :: \0+1", join_prompts("a\0 :: ", "\0+1"));
+}
+
+#[test]
+fn test_render_trailing_space() {
+    for shell in &[Shell::Zsh, Shell::Bash] {
+        let separator = || {
+            vec![Segment::new(
+                SegmentKind::Separator,
+                format!(
+                    "{}::{}",
+                    Color::Green.to_str(false, shell),
+                    Attribute::Reset.to_str(shell)
+                ),
+            )]
+        };
+        assert!(render(separator(), true, true)
+            .ends_with(&format!("{} ", Attribute::Reset.to_str(shell))));
+        assert!(render(separator(), false, true).ends_with(Attribute::Reset.to_str(shell)));
+        assert_eq!(":: ", render(separator(), true, false));
+    }
+}
+
+/// Drop segments (lowest priority first) and then switch to their compact variants until the
+/// prompt is at most `max_width` columns wide, or there's nothing left to try. A segment with
+/// a compact variant switches to it before being dropped, e.g. the branch loses the "on".
+fn fit_segments(segments: &mut Vec<Segment>, max_width: usize) {
+    let width = |segments: &[Segment]| {
+        segments
+            .iter()
+            .map(|x| display_width(&x.text))
+            .sum::<usize>()
+    };
+    while width(segments) > max_width {
+        let lowest = segments
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.priority < u8::MAX)
+            .min_by_key(|(_, x)| x.priority)
+            .map(|(idx, _)| idx);
+        match lowest {
+            Some(idx) => match segments[idx].compact.take() {
+                Some(compact) => segments[idx].text = compact,
+                None => {
+                    segments.remove(idx);
+                }
+            },
+            None => break,
+        }
+    }
+    if width(segments) > max_width {
+        for segment in segments.iter_mut() {
+            if let Some(compact) = segment.compact.take() {
+                segment.text = compact;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fit_segments() {
+    let segments = || {
+        vec![
+            Segment {
+                compact: Some("c ".into()),
+                ..Segment::new(SegmentKind::Path, "~/a/b/c ".into())
+            },
+            Segment::new(SegmentKind::Branch, "on main ".into()),
+            Segment::new(SegmentKind::Timing, "took 5s ".into()),
+            Segment::new(SegmentKind::Separator, "::".into()),
+        ]
+    };
+    let texts = |segments: Vec<Segment>| segments.into_iter().map(|x| x.text).collect::<String>();
+
+    let mut s = segments();
+    fit_segments(&mut s, 100);
+    assert_eq!("~/a/b/c on main took 5s ::", texts(s));
+    let mut s = segments();
+    fit_segments(&mut s, 18);
+    assert_eq!("~/a/b/c on main ::", texts(s));
+    let mut s = segments();
+    fit_segments(&mut s, 10);
+    assert_eq!("~/a/b/c ::", texts(s));
+    let mut s = segments();
+    fit_segments(&mut s, 5);
+    assert_eq!("c ::", texts(s));
+
+    // With the timing raised above the branch the latter goes first.
+    let mut s = segments();
+    s[2].priority = 25;
+    fit_segments(&mut s, 18);
+    assert_eq!("~/a/b/c took 5s ::", texts(s));
+
+    // The branch loses the connector before being dropped.
+    let with_compact_branch = || {
+        let mut s = segments();
+        s[1].compact = Some("main ".into());
+        s
+    };
+    let mut s = with_compact_branch();
+    fit_segments(&mut s, 18);
+    assert_eq!("~/a/b/c on main ::", texts(s));
+    let mut s = with_compact_branch();
+    fit_segments(&mut s, 15);
+    assert_eq!("~/a/b/c main ::", texts(s));
+    let mut s = with_compact_branch();
+    fit_segments(&mut s, 14);
+    assert_eq!("~/a/b/c ::", texts(s));
+}
+
+/// Flags that `init` accepts and forwards as-is to `prompt`, as `(name, flag)` pairs.
+const FORWARDED_FLAGS: [(&str, &str); 70] = [
+    ("unicode", "-u"),
+    ("short_path", "-p"),
+    ("ascii_separator", "--ascii-separator"),
+    ("separator_success", "--separator-success"),
+    ("separator_error", "--separator-error"),
+    ("root_symbol", "--root-symbol"),
+    ("root_position", "--root-position"),
+    ("unicode_branch", "--unicode-branch"),
+    ("signed", "--signed"),
+    ("git_identity", "--git-identity"),
+    ("no_timing", "--no-timing"),
+    ("duration_format", "--duration-format"),
+    ("time_icon", "--time-icon"),
+    ("time_icon_bands", "--time-icon-bands"),
+    ("no_trailing_space", "--no-trailing-space"),
+    ("status_summary", "--status-summary"),
+    ("detailed_status", "--detailed-status"),
+    ("todo_count", "--todo-count"),
+    ("adaptive_path", "--adaptive-path"),
+    ("truncate", "--truncate"),
+    ("load", "--load"),
+    ("limits", "--limits"),
+    ("self_time", "--self-time"),
+    ("diskspace", "--diskspace"),
+    ("submodules", "--submodules"),
+    ("show_signals", "--show-signals"),
+    ("toolchains", "--toolchains"),
+    ("sticky_errors", "--sticky-errors"),
+    ("word_in", "--word-in"),
+    ("word_on", "--word-on"),
+    ("word_took", "--word-took"),
+    ("lang", "--lang"),
+    ("nix", "--nix"),
+    ("branch_min_width", "--branch-min-width"),
+    ("branch_max_width", "--branch-max-width"),
+    ("quote_paths", "--quote-paths"),
+    ("conda", "--conda"),
+    ("ssh_agent", "--ssh-agent"),
+    ("conda_show_base", "--conda-show-base"),
+    ("branch_symbols", "--branch-symbols"),
+    ("compact", "--compact"),
+    ("minimal", "--minimal"),
+    ("hyperlink_path", "--hyperlink-path"),
+    ("compare_remote", "--compare-remote"),
+    ("incoming", "--incoming"),
+    ("exclude_git", "--exclude-git"),
+    ("no_auto_network_skip", "--no-auto-network-skip"),
+    ("git_on_right", "--git-on-right"),
+    ("env_flags", "--env-flags"),
+    ("show_local", "--show-local"),
+    ("warn_default_branch", "--warn-default-branch"),
+    ("operation", "--operation"),
+    ("sparse", "--sparse"),
+    ("warn_hooks", "--warn-hooks"),
+    ("worktrees", "--worktrees"),
+    ("direnv", "--direnv"),
+    ("show_suspended", "--show-suspended"),
+    ("theme", "--theme"),
+    ("color", "--color"),
+    ("always_repo_name", "--always-repo-name"),
+    ("path_length", "--path-length"),
+    ("dim_outside_repo", "--dim-outside-repo"),
+    ("show_readonly", "--show-readonly"),
+    ("mood", "--mood"),
+    ("updates", "--updates"),
+    ("commit_count", "--commit-count"),
+    ("head_age", "--head-age"),
+    ("bright_path", "--bright-path"),
+    ("constant_path_color", "--constant-path-color"),
+    ("bright_branch", "--bright-branch"),
+];
+
+/// The `FORWARDED_FLAGS` without a value, by bit of the mask that `init` passes to `--flags`
+/// instead of the flags themselves. The init code evaluated by running shells keeps passing the
+/// masks of the sprompt that generated it, so new flags are only ever appended.
+const FLAG_BITS: [&str; 48] = [
+    "unicode",
+    "short_path",
+    "ascii_separator",
+    "unicode_branch",
+    "signed",
+    "git_identity",
+    "no_timing",
+    "time_icon",
+    "no_trailing_space",
+    "detailed_status",
+    "adaptive_path",
+    "load",
+    "limits",
+    "self_time",
+    "submodules",
+    "show_signals",
+    "toolchains",
+    "sticky_errors",
+    "nix",
+    "quote_paths",
+    "conda",
+    "ssh_agent",
+    "conda_show_base",
+    "compact",
+    "hyperlink_path",
+    "incoming",
+    "no_auto_network_skip",
+    "git_on_right",
+    "show_local",
+    "warn_default_branch",
+    "operation",
+    "sparse",
+    "warn_hooks",
+    "always_repo_name",
+    "dim_outside_repo",
+    "show_readonly",
+    "mood",
+    "updates",
+    "commit_count",
+    "head_age",
+    "bright_path",
+    "constant_path_color",
+    "bright_branch",
+    "direnv",
+    "show_suspended",
+    "worktrees",
+    "minimal",
+    "todo_count",
+];
+
+/// The `--flags` mask, in hex, of the `FLAG_BITS` among `names` present in `matches`. `None`
+/// if there are none.
+fn encode_flags(matches: &ArgMatches, names: &[&str]) -> Option<String> {
+    let mask = FLAG_BITS
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| names.contains(name) && matches.is_present(name))
+        .fold(0u64, |mask, (i, _)| mask | 1 << i);
+    if mask == 0 {
+        None
+    } else {
+        Some(format!("{:x}", mask))
+    }
+}
+
+/// The flags in a `--flags` mask, `None` if it isn't hex or has bits no flag is assigned to,
+/// e.g. from a newer sprompt.
+fn decode_flags(mask: &str) -> Option<Vec<&'static str>> {
+    let mask = u64::from_str_radix(mask, 16).ok()?;
+    if mask.checked_shr(FLAG_BITS.len() as u32).unwrap_or(0) != 0 {
+        return None;
+    }
+    let flags = FLAG_BITS
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| mask & 1 << i != 0)
+        .filter_map(|(_, name)| FORWARDED_FLAGS.iter().find(|(x, _)| x == name))
+        .map(|(_, flag)| *flag)
+        .collect();
+    Some(flags)
+}
+
+fn flags_validator(s: String) -> Result<(), String> {
+    match decode_flags(&s) {
+        Some(_) => Ok(()),
+        None => Err("The argument must be a mask of flags generated by `sprompt init`".into()),
+    }
+}
+
+/// Replace every `--flags MASK` in `args` with the flags it stands for, so that the rest of
+/// sprompt only deals with the individual flags. Invalid masks are left for clap to reject.
+fn expand_flags(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut expanded = Vec::new();
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let (mask, separate) = match arg.to_str() {
+            Some("--flags") => (args.peek().and_then(|x| x.to_str()), true),
+            Some(x) => (x.strip_prefix("--flags="), false),
+            None => (None, false),
+        };
+        match mask.and_then(decode_flags) {
+            Some(flags) => {
+                if separate {
+                    args.next();
+                }
+                expanded.extend(flags.into_iter().map(OsString::from));
+            }
+            None => expanded.push(arg),
+        }
+    }
+    expanded
+}
+
+#[test]
+fn test_flag_bits() {
+    assert!(FLAG_BITS.len() <= 64);
+    let args = forwarded_args();
+    let boolean = FORWARDED_FLAGS
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| {
+            let arg = args.iter().find(|x| x.b.name == *name).unwrap();
+            !arg.is_set(clap::ArgSettings::TakesValue)
+        })
+        .collect::<Vec<_>>();
+    for name in FLAG_BITS.iter() {
+        assert!(
+            boolean.contains(name),
+            "{} isn't a forwarded flag without a value",
+            name
+        );
+    }
+    for name in boolean {
+        assert!(
+            FLAG_BITS.contains(&name),
+            "{} has no bit in FLAG_BITS",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_flags_round_trip() {
+    let round_trip = |args: &[&str]| {
+        let matches = app().get_matches_from(
+            [
+                "sprompt",
+                "prompt",
+                "-s",
+                "zsh",
+                "-e",
+                "0",
+                "--elapsed-seconds",
+                "0",
+            ]
+            .iter()
+            .chain(args),
+        );
+        let matches = matches.subcommand_matches("prompt").unwrap();
+        let mask = encode_flags(matches, &FLAG_BITS);
+        let mut flags = mask.as_deref().map_or(vec![], |x| decode_flags(x).unwrap());
+        flags.sort_unstable();
+        flags
+    };
+    assert_eq!(Vec::<&str>::new(), round_trip(&[]));
+    assert_eq!(vec!["-u"], round_trip(&["-u"]));
+    assert_eq!(
+        vec!["--warn-hooks", "-p", "-u"],
+        round_trip(&["--warn-hooks", "-p", "-u", "--theme", "nord"])
+    );
+    let all = FLAG_BITS
+        .iter()
+        .map(|name| FORWARDED_FLAGS.iter().find(|(x, _)| x == name).unwrap().1)
+        .collect::<Vec<_>>();
+    let mut sorted = all.clone();
+    sorted.sort_unstable();
+    assert_eq!(sorted, round_trip(&all));
+    assert_eq!(None, decode_flags("xyz"));
+    assert_eq!(None, decode_flags(""));
+    assert_eq!(
+        None,
+        decode_flags(&format!("{:x}", 1u64 << FLAG_BITS.len()))
+    );
+}
+
+#[test]
+fn test_expand_flags() {
+    let expand = |args: &[&str]| {
+        expand_flags(args.iter().map(OsString::from))
+            .into_iter()
+            .map(|x| x.into_string().unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        vec!["prompt", "-u", "-p", "--theme", "nord"],
+        expand(&["prompt", "--flags", "3", "--theme", "nord"])
+    );
+    assert_eq!(vec!["prompt", "-p"], expand(&["prompt", "--flags=2"]));
+    assert_eq!(
+        vec!["prompt", "--flags", "x"],
+        expand(&["prompt", "--flags", "x"])
+    );
+    assert_eq!(vec!["prompt", "--flags"], expand(&["prompt", "--flags"]));
+    assert!(app()
+        .get_matches_from_safe(expand(&["sprompt", "prompt", "-s", "bash", "--flags", "x"]))
+        .is_err());
+}
+
+/// Definitions of the `FORWARDED_FLAGS`, shared by `prompt` and `init`.
+fn forwarded_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("flags")
+            .long("flags")
+            .takes_value(true)
+            .value_name("MASK")
+            .validator(flags_validator)
+            .help("Several flags without a value at once, as passed by the init code"),
+        Arg::with_name("unicode")
+            .long("unicode")
+            .short("u")
+            .help("Use unicode symbols"),
+        Arg::with_name("ascii_separator")
+            .long("ascii-separator")
+            .help("Keep the `::` separator even with --unicode"),
+        Arg::with_name("separator_success")
+            .long("separator-success")
+            .takes_value(true)
+            .help("Separator after a successful command, instead of `::` or `❯`"),
+        Arg::with_name("separator_error")
+            .long("separator-error")
+            .takes_value(true)
+            .help("Separator after a failed command, instead of `::` or `❯`"),
+        Arg::with_name("root_symbol")
+            .long("root-symbol")
+            .takes_value(true)
+            .help("Symbol shown when running as root, instead of `root in` before the path or `#` as the separator"),
+        Arg::with_name("root_position")
+            .long("root-position")
+            .takes_value(true)
+            .possible_values(&["prefix", "separator"])
+            .help("Show that sprompt runs as root before the path, or by replacing the separator [default: prefix]"),
+        Arg::with_name("unicode_branch")
+            .long("unicode-branch")
+            .help("Use the unicode branch symbol even without --unicode"),
+        Arg::with_name("short_path")
+            .long("short-path")
+            .short("p")
+            .help("Show the current path in a reduced form"),
+        Arg::with_name("signed")
+            .long("signed")
+            .help("Show a marker when the HEAD commit is signed"),
+        Arg::with_name("no_timing")
+            .long("no-timing")
+            .help("Never show how long the last command took"),
+        Arg::with_name("no_trailing_space")
+            .long("no-trailing-space")
+            .help("Don't put a space after the separator"),
+        Arg::with_name("status_summary")
+            .long("status-summary")
+            .takes_value(true)
+            .min_values(0)
+            .use_delimiter(true)
+            .possible_values(&StatusPart::SUPPORTED)
+            .help("Show a compact cluster with the number of dirty files, commits ahead of upstream and conflicts, optionally only the given parts"),
+        Arg::with_name("adaptive_path")
+            .long("adaptive-path")
+            .help("Show the full path, shortening it only when it takes too much of the terminal width (needs --columns)"),
+        Arg::with_name("truncate")
+            .long("truncate")
+            .takes_value(true)
+            .possible_values(&["start", "middle"])
+            .requires("adaptive_path")
+            .help("How --adaptive-path shortens the path: drop components from the start, or from the middle keeping the first and last ones [default: start]"),
+        Arg::with_name("load")
+            .long("load")
+            .help("Show the load average when the system is overloaded"),
+        Arg::with_name("limits")
+            .long("limits")
+            .help("Show the open files when they're close to the limit (ulimit -n)"),
+        Arg::with_name("submodules")
+            .long("submodules")
+            .help("Show a marker when a submodule is modified or not initialized"),
+        Arg::with_name("show_suspended")
+            .long("show-suspended")
+            .help("Show the separator in a different color after suspending a command, e.g. with <ctrl-z>"),
+        Arg::with_name("show_signals")
+            .long("show-signals")
+            .help("Show the signal that killed the last command, e.g. SIGINT(130)"),
+        Arg::with_name("toolchains")
+            .long("toolchains")
+            .help("Show the node/rust version when inside one of their projects"),
+        Arg::with_name("sticky_errors")
+            .long("sticky-errors")
+            .help("Keep showing a marker for one more prompt after a command fails"),
+        Arg::with_name("word_in")
+            .long("word-in")
+            .takes_value(true)
+            .help("Word between the root marker and the path, overrides --lang"),
+        Arg::with_name("word_on")
+            .long("word-on")
+            .takes_value(true)
+            .help("Word before the branch, overrides --lang"),
+        Arg::with_name("word_took")
+            .long("word-took")
+            .takes_value(true)
+            .help("Word before the elapsed time, overrides --lang"),
+        Arg::with_name("lang")
+            .long("lang")
+            .takes_value(true)
+            .help("Language of the words in the prompt, e.g. `it` [default: from $LANG]"),
+        Arg::with_name("nix")
+            .long("nix")
+            .help("Show when inside a nix shell"),
+        Arg::with_name("direnv")
+            .long("direnv")
+            .help("Show when the .envrc of the directory is loaded by direnv, or blocked until `direnv allow`"),
+        Arg::with_name("branch_symbols")
+            .long("branch-symbols")
+            .takes_value(true)
+            .min_values(0)
+            .use_delimiter(true)
+            .validator(branch_symbol_validator)
+            .help("Change the branch symbol based on the prefix of the branch name, optionally overriding the defaults with PREFIX=SYMBOL pairs (`detached` for a detached HEAD)"),
+        Arg::with_name("compact")
+            .long("compact")
+            .conflicts_with_all(&["word_in", "word_on", "word_took"])
+            .help("Leave out the connector words (in, on, took), relying on colors alone"),
+        Arg::with_name("minimal")
+            .long("minimal")
+            .help("Show only the path and the separator, whatever other segments are enabled, e.g. while screen sharing. Running as root is still shown"),
+        Arg::with_name("hyperlink_path")
+            .long("hyperlink-path")
+            .help("Make the path a link to the directory, in terminals supporting OSC 8 hyperlinks"),
+        Arg::with_name("compare_remote")
+            .long("compare-remote")
+            .takes_value(true)
+            .help("Show how the current branch diverged from its upstream and from the same branch on this remote"),
+        Arg::with_name("incoming")
+            .long("incoming")
+            .help("Show the commits of the upstream not merged yet, as of the last fetch (sprompt never fetches)"),
+        Arg::with_name("always_repo_name")
+            .long("always-repo-name")
+            .help("With --short-path, always start from the repo name, even when deep inside the repo"),
+        Arg::with_name("path_length")
+            .long("path-length")
+            .takes_value(true)
+            .validator(non_zero_usize_validator)
+            .help("Number of directories shown by --short-path [default: 3]"),
+        Arg::with_name("dim_outside_repo")
+            .long("dim-outside-repo")
+            .help("Dim the directories of the full path that are above the repo"),
+        Arg::with_name("show_readonly")
+            .long("show-readonly")
+            .help("Show a lock when the current directory isn't writable"),
+        Arg::with_name("mood")
+            .long("mood")
+            .help("Show a happy face after a streak of successful commands, a sad one after a failure"),
+        Arg::with_name("bright_path")
+            .long("bright-path")
+            .help("Use the bright variant of the path color"),
+        Arg::with_name("constant_path_color")
+            .long("constant-path-color")
+            .help("Always show the path in cyan, instead of red in read-only directories and yellow outside of repos with --short-path"),
+        Arg::with_name("bright_branch")
+            .long("bright-branch")
+            .help("Use the bright variant of the branch color"),
+        Arg::with_name("commit_count")
+            .long("commit-count")
+            .help("Show the number of commits of the current branch (walks the history, cached by HEAD)"),
+        Arg::with_name("head_age")
+            .long("head-age")
+            .help("Show how long ago the HEAD commit was committed"),
+        Arg::with_name("updates")
+            .long("updates")
+            .help("Show the number of pending OS updates, read from $SPROMPT_UPDATES_FILE"),
+        Arg::with_name("theme")
+            .long("theme")
+            .takes_value(true)
+            .possible_values(&Theme::SUPPORTED)
+            .help("Color scheme, individual flags like --bright-path still apply [default: default]"),
+        Arg::with_name("color")
+            .long("color")
+            .takes_value(true)
+            .possible_values(&ColorMode::SUPPORTED)
+            .help("When to print colors and other escapes, `auto` leaves them out with $NO_COLOR or TERM=dumb [default: auto]"),
+        Arg::with_name("diskspace")
+            .long("diskspace")
+            .takes_value(true)
+            .min_values(0)
+            .max_values(1)
+            .value_name("PERCENT")
+            .validator(percent_validator)
+            .help("Show the free space of the filesystem of the cwd when it's below PERCENT [default: 10]"),
+        Arg::with_name("duration_format")
+            .long("duration-format")
+            .takes_value(true)
+            .possible_values(&DurationFormat::SUPPORTED)
+            .help("How to show the elapsed time: `auto` like 2m 5s, `seconds` like 125s, `compact` like 2m [default: auto]"),
+        Arg::with_name("time_icon")
+            .long("time-icon")
+            .help("Show an icon telling whether the last command was fast, slow or in between"),
+        Arg::with_name("time_icon_bands")
+            .long("time-icon-bands")
+            .takes_value(true)
+            .value_name("FAST,SLOW")
+            .requires("time_icon")
+            .validator(time_icon_bands_validator)
+            .help("Seconds below which a command is fast and from which it's slow, for --time-icon [default: 2,30]"),
+        Arg::with_name("ssh_agent")
+            .long("ssh-agent")
+            .help("Show whether an ssh agent is running and has keys (asks it at most once a minute)"),
+        Arg::with_name("show_local")
+            .long("show-local")
+            .help("Show (local) after the branch when the repo has no remote, as a reminder to push somewhere"),
+        Arg::with_name("warn_default_branch")
+            .long("warn-default-branch")
+            .help("Show a warning on the default branch (origin/HEAD, or else main or master), as a nudge to create a feature branch"),
+        Arg::with_name("operation")
+            .long("operation")
+            .help("Show the git operation in progress, e.g. `rebase 3/10` or `merge`"),
+        Arg::with_name("sparse")
+            .long("sparse")
+            .help("Show when the repo is a sparse checkout, where some files are missing on purpose"),
+        Arg::with_name("warn_hooks")
+            .long("warn-hooks")
+            .help("Show a warning when the repo has git hooks installed, which run on commit, push, etc."),
+        Arg::with_name("worktrees")
+            .long("worktrees")
+            .help("Show how many worktrees the repo has, when there is more than one"),
+        Arg::with_name("detailed_status")
+            .long("detailed-status")
+            .help("Show the number of staged and unstaged changes separately"),
+        Arg::with_name("todo_count")
+            .long("todo-count")
+            .help("Show how many lines with TODO or FIXME the uncommitted changes add (scans the diff)"),
+        Arg::with_name("git_identity")
+            .long("git-identity")
+            .help("Show the email used for commits when the repo overrides the global one"),
+        Arg::with_name("env_flags")
+            .long("env-flags")
+            .takes_value(true)
+            .value_name("PREFIX")
+            .help("Show the environment variables starting with PREFIX that are set to a true value, e.g. FEATURE_"),
+        Arg::with_name("exclude_git")
+            .long("exclude-git")
+            .takes_value(true)
+            .value_name("PATH")
+            .multiple(true)
+            .number_of_values(1)
+            .use_delimiter(true)
+            .help("Don't look for a git repo in PATH and below, e.g. slow network mounts (repeatable)"),
+        Arg::with_name("no_auto_network_skip")
+            .long("no-auto-network-skip")
+            .help("Look for a git repo even on network filesystems (NFS, SMB, sshfs, ...), where it's skipped by default as it can be very slow"),
+        Arg::with_name("git_on_right")
+            .long("git-on-right")
+            .help("Leave the git status segments to the right prompt, see the rprompt subcommand. Ignored in bash, which has no right prompt"),
+        Arg::with_name("self_time")
+            .long("self-time")
+            .help("Show how long sprompt took to render the prompt, startup of the process excluded"),
+        Arg::with_name("conda")
+            .long("conda")
+            .help("Show the active conda environment"),
+        Arg::with_name("conda_show_base")
+            .long("conda-show-base")
+            .requires("conda")
+            .help("Show the conda environment even when it's `base`"),
+        Arg::with_name("branch_min_width")
+            .long("branch-min-width")
+            .takes_value(true)
+            .validator(usize_validator)
+            .help("Pad the branch name with spaces to at least this many columns, up to --branch-max-width"),
+        Arg::with_name("branch_max_width")
+            .long("branch-max-width")
+            .takes_value(true)
+            .validator(non_zero_usize_validator)
+            .help("Truncate the branch name to at most this many columns, the ellipsis included"),
+        Arg::with_name("quote_paths")
+            .long("quote-paths")
+            .help("Quote path components containing whitespace or special characters"),
+    ]
+}
+
+/// Arguments of `prompt`, shared with `debug`.
+fn prompt_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("exit_code")
+            .long("exit-code")
+            .short("e")
+            .takes_value(true)
+            .help("Last command exit code")
+            .required_unless_one(&["preview", "continuation"]),
+        Arg::with_name("shell")
+            .long("shell")
+            .short("s")
+            .takes_value(true)
+            .help("The shell where the prompt will be shown")
+            .required(true)
+            .possible_values(&Shell::SUPPORTED),
+        Arg::with_name("elapsed_seconds")
+            .long("elapsed-seconds")
+            .takes_value(true)
+            .help("Last command's execution time in seconds")
+            .required_unless_one(&[
+                "no_timing",
+                "elapsed_ms",
+                "start_ns",
+                "preview",
+                "continuation",
+            ])
+            .validator(u64_validator),
+        Arg::with_name("preview")
+            .long("preview")
+            .help("Show the prompt for a sample repo and command, handy to try themes and flags"),
+        Arg::with_name("continuation")
+            .long("continuation")
+            .help("Show the secondary prompt, for commands spanning several lines"),
+        Arg::with_name("with_rprompt")
+            .long("with-rprompt")
+            .help("Also output the right prompt (see --git-on-right) after the left one, separated by a NUL byte"),
+        Arg::with_name("ps1_escapes")
+            .long("ps1-escapes")
+            .help("Mark the escapes so that readline knows the width of the prompt, when it's set as PS1 in bash"),
+        Arg::with_name("elapsed_ms")
+            .long("elapsed-ms")
+            .takes_value(true)
+            .help("Last command's execution time in milliseconds")
+            .conflicts_with("elapsed_seconds")
+            .validator(u64_validator),
+        Arg::with_name("start_ns")
+            .long("start-ns")
+            .takes_value(true)
+            .help("When the last command started, in nanoseconds since the Unix epoch")
+            .conflicts_with_all(&["elapsed_seconds", "elapsed_ms"])
+            .validator(u64_validator),
+        Arg::with_name("last_maxrss")
+            .long("last-maxrss")
+            .takes_value(true)
+            .help("Last command's peak memory usage in kilobytes, shown with the elapsed time")
+            .validator(u64_validator),
+        Arg::with_name("last_cpu")
+            .long("last-cpu")
+            .takes_value(true)
+            .help("Last command's CPU usage in percent, shown with the elapsed time")
+            .validator(u64_validator),
+        Arg::with_name("max_width")
+            .long("max-width")
+            .takes_value(true)
+            .help("Drop or shorten segments until the prompt fits in this many columns")
+            .validator(usize_or_empty_validator),
+        Arg::with_name("columns")
+            .long("columns")
+            .takes_value(true)
+            .help("Width of the terminal")
+            .validator(usize_or_empty_validator),
+        Arg::with_name("profile")
+            .long("profile")
+            .takes_value(true)
+            .env("SPROMPT_PROFILE")
+            .help("Append timing data of each prompt, as JSON lines, to this file"),
+        Arg::with_name("dirs")
+            .long("dirs")
+            .takes_value(true)
+            .help("Depth of the directory stack of the shell, the current directory included")
+            .validator(usize_validator),
+        Arg::with_name("non_interactive")
+            .long("non-interactive")
+            .help("The shell reads the commands from a pipe or a file rather than a terminal, shown as `[script]`"),
+        Arg::with_name("cwd")
+            .long("cwd")
+            .takes_value(true)
+            .validator(directory_validator)
+            .help("Compute the prompt for this directory instead of the current one"),
+        Arg::with_name("write_cache")
+            .long("write-cache")
+            .takes_value(true)
+            .help("Write the prompt to this file instead of printing it"),
+    ]
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new(crate_name!())
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .settings(&[AppSettings::SubcommandRequired])
+        .global_settings(&[AppSettings::ColorAuto, AppSettings::ColoredHelp])
+        .subcommand(
+            SubCommand::with_name("prompt")
+                .about("Output the prompt string")
+                .args(&prompt_args())
+                .args(&forwarded_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("rprompt")
+                .about("Output the right prompt string: the git status segments, see --git-on-right")
+                .args(&prompt_args())
+                .args(&forwarded_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Explain which segments are shown, and why the others aren't")
+                .args(&prompt_args())
+                .args(&forwarded_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Output code to be evaluated by the shell to init the prompt")
+                .arg(
+                    Arg::with_name("shell")
+                        .long("shell")
+                        .short("s")
+                        .takes_value(true)
+                        .help("The shell for which to output the init code")
+                        .required_unless("universal")
+                        .possible_values(&Shell::SUPPORTED),
+                )
+                .arg(
+                    Arg::with_name("universal")
+                        .long("universal")
+                        .conflicts_with("shell")
+                        .help("Output POSIX code that runs the init of the shell it's evaluated in, for rc files shared by bash and zsh"),
+                )
+                .args(&forwarded_args())
+                .arg(
+                    Arg::with_name("function_name")
+                        .long("function-name")
+                        .takes_value(true)
+                        .help("Prefix of the names of the generated functions and variables [default: _sprompt]")
+                        .validator(shell_identifier_validator),
+                )
+                .arg(
+                    Arg::with_name("fit_width")
+                        .long("fit-width")
+                        .help("Drop or shorten segments until the prompt fits in the terminal"),
+                )
+                .arg(
+                    Arg::with_name("precise_timing")
+                        .long("precise-timing")
+                        .conflicts_with("no_timing")
+                        .help("Time commands with sub-second precision using $EPOCHREALTIME (zsh, bash 5+)"),
+                )
+                .arg(
+                    Arg::with_name("ps1")
+                        .long("ps1")
+                        .help("bash: set the prompt as PS1 instead of printing it from PROMPT_COMMAND, so PS1 doesn't need a leading space, implied by --no-trailing-space"),
+                )
+                .arg(
+                    Arg::with_name("dir_stack")
+                        .long("dir-stack")
+                        .help("Show the depth of the directory stack (pushd/popd)"),
+                )
+                .arg(
+                    Arg::with_name("show_script")
+                        .long("show-script")
+                        .help("Show `[script]` when the stdin of the shell isn't a terminal, as of when the init code is evaluated"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("Write the init code to this file instead of stdout"),
+                )
+                .arg(
+                    Arg::with_name("cache")
+                        .long("cache")
+                        .takes_value(true)
+                        .help("Show the prompt cached in this file, refreshing it in the background. The prompt lags one command behind"),
+                )
+                .arg(
+                    Arg::with_name("cache_on_cwd")
+                        .long("cache-on-cwd")
+                        .conflicts_with_all(&["no_timing", "cache"])
+                        .help("Only run sprompt again when a command ran, failed or changed the directory, pressing enter on an empty line shows the same prompt"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("uninstall")
+                .about("Output code to be evaluated by the shell to remove the prompt from the current session")
+                .arg(
+                    Arg::with_name("shell")
+                        .long("shell")
+                        .short("s")
+                        .takes_value(true)
+                        .help("The shell for which to output the code")
+                        .required(true)
+                        .possible_values(&Shell::SUPPORTED),
+                )
+                .arg(
+                    Arg::with_name("function_name")
+                        .long("function-name")
+                        .takes_value(true)
+                        .help("The --function-name passed to init [default: _sprompt]")
+                        .validator(shell_identifier_validator),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump-escapes")
+                .about("Print every color and attribute with its escape sequence, for checking the support of a shell")
+                .setting(AppSettings::Hidden)
+                .arg(
+                    Arg::with_name("shell")
+                        .long("shell")
+                        .short("s")
+                        .takes_value(true)
+                        .help("The shell whose escapes to print")
+                        .required(true)
+                        .possible_values(&Shell::SUPPORTED),
+                ),
+        )
+}
+
+/// Arguments that the init code passes to `sprompt prompt`, besides the shell, exit code and
+/// elapsed time.
+fn init_args(matches: &ArgMatches, shell: &Shell) -> String {
+    let mut args = String::from(" ");
+    // Without a right prompt the git status stays on the left.
+    let flags = FORWARDED_FLAGS
+        .iter()
+        .filter(|(name, _)| *shell != Shell::Bash || *name != "git_on_right")
+        .copied()
+        .collect::<Vec<_>>();
+    let names = flags.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    if let Some(mask) = encode_flags(matches, &names) {
+        let _ = write!(&mut args, "--flags {} ", mask);
+    }
+    let flags = flags
+        .into_iter()
+        .filter(|(name, _)| !FLAG_BITS.contains(name))
+        .collect::<Vec<_>>();
+    push_flags(&mut args, matches, &flags, shell);
+    if matches.is_present("fit_width") {
+        let _ = write!(&mut args, "--max-width {} ", shell.var("COLUMNS"));
+    }
+    if matches.is_present("adaptive_path") {
+        let _ = write!(&mut args, "--columns {} ", shell.var("COLUMNS"));
+    }
+    if matches.is_present("dir_stack") {
+        // Unlike bash's `DIRSTACK`, zsh's `dirstack` doesn't include the current directory.
+        let depth = match shell {
+            Shell::Zsh => r#""\$(( \${#dirstack} + 1 ))""#,
+            Shell::Bash => r#""${#DIRSTACK[@]}""#,
+        };
+        let _ = write!(&mut args, "--dirs {} ", depth);
+    }
+    // `init` runs in a command substitution, which shares the stdin of the shell.
+    if matches.is_present("show_script") && !std::io::stdin().is_terminal() {
+        args.push_str("--non-interactive ");
+    }
+    args.trim_end().into()
+}
+
+/// Append the `flags` present in `matches`, with their values, each followed by a space.
+fn push_flags(args: &mut String, matches: &ArgMatches, flags: &[(&str, &str)], shell: &Shell) {
+    for (name, flag) in flags {
+        if matches.is_present(name) {
+            args.push_str(flag);
+            let values = matches
+                .values_of(name)
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>();
+            if !values.is_empty() {
+                let _ = write!(args, " {}", shell.quote(&values.join(",")));
+            }
+            args.push(' ');
+        }
+    }
+}
+
+/// Flags forwarded to `sprompt prompt --continuation`, the ones changing how it looks. The
+/// others may not even be valid there: bash renders `PS2` once while evaluating the init code,
+/// e.g. with `COLUMNS` still unset for `--max-width "$COLUMNS"`.
+const CONTINUATION_FLAGS: [(&str, &str); 4] = [
+    ("unicode", "-u"),
+    ("theme", "--theme"),
+    ("color", "--color"),
+    ("no_trailing_space", "--no-trailing-space"),
+];
+
+/// Arguments of `sprompt prompt --continuation` in the init code, see `CONTINUATION_FLAGS`.
+fn continuation_args(matches: &ArgMatches, shell: &Shell) -> String {
+    let mut args = String::from(" ");
+    push_flags(&mut args, matches, &CONTINUATION_FLAGS, shell);
+    args.trim_end().into()
+}
+
+#[test]
+fn test_continuation_args() {
+    let matches = app().get_matches_from([
+        "sprompt",
+        "init",
+        "-s",
+        "bash",
+        "--fit-width",
+        "--adaptive-path",
+        "--dir-stack",
+        "-u",
+        "-p",
+        "--theme",
+        "mono",
+    ]);
+    let matches = matches.subcommand_matches("init").unwrap();
+    assert_eq!(
+        " -u --theme 'mono'",
+        continuation_args(matches, &Shell::Bash)
+    );
+    let opts = InitOptions {
+        continuation_args: continuation_args(matches, &Shell::Bash),
+        ..Default::default()
+    };
+    let code = Shell::Bash.init_code(&init_args(matches, &Shell::Bash), &opts);
+    assert!(code.contains("--max-width"));
+    assert!(code.ends_with(r#"PS2="$(sprompt prompt --continuation -s bash -u --theme 'mono')""#));
+}
+
+/// Flags of `init` that only affect the init code, as `(name, flag)` pairs.
+const INIT_FLAGS: [(&str, &str); 8] = [
+    ("function_name", "--function-name"),
+    ("fit_width", "--fit-width"),
+    ("precise_timing", "--precise-timing"),
+    ("ps1", "--ps1"),
+    ("dir_stack", "--dir-stack"),
+    ("show_script", "--show-script"),
+    ("cache", "--cache"),
+    ("cache_on_cwd", "--cache-on-cwd"),
+];
+
+/// POSIX code running `sprompt init` for the shell it's evaluated in, with the same flags. It
+/// has to be sourced by bash or zsh, other shells are left alone.
+fn universal_init_code(matches: &ArgMatches) -> String {
+    let mut args = String::from(" ");
+    // Single quotes work the same in both shells inside `$(...)`.
+    push_flags(&mut args, matches, &FORWARDED_FLAGS, &Shell::Bash);
+    push_flags(&mut args, matches, &INIT_FLAGS, &Shell::Bash);
+    let args = args.trim_end();
+    format!(
+        r#"if [ -n "${{ZSH_VERSION-}}" ]; then
+    eval "$(sprompt init -s zsh{args})"
+elif [ -n "${{BASH_VERSION-}}" ]; then
+    eval "$(sprompt init -s bash{args})"
+fi"#,
+        args = args
+    )
+}
+
+#[test]
+fn test_universal_init_code() {
+    let matches = app().get_matches_from([
+        "sprompt",
+        "init",
+        "--universal",
+        "-p",
+        "--word-on",
+        "at",
+        "--fit-width",
+    ]);
+    let code = universal_init_code(matches.subcommand_matches("init").unwrap());
+    assert_eq!(
+        r#"if [ -n "${ZSH_VERSION-}" ]; then
+    eval "$(sprompt init -s zsh -p --word-on 'at' --fit-width)"
+elif [ -n "${BASH_VERSION-}" ]; then
+    eval "$(sprompt init -s bash -p --word-on 'at' --fit-width)"
+fi"#,
+        code
+    );
+    assert!(app()
+        .get_matches_from_safe(["sprompt", "init", "--universal", "-s", "zsh"])
+        .is_err());
+}
+
+#[test]
+fn test_init_args() {
+    let init_args_from = |args: &[&str]| {
+        let matches = app().get_matches_from(["sprompt", "init"].iter().chain(args));
+        let matches = matches.subcommand_matches("init").unwrap();
+        init_args(
+            matches,
+            &Shell::try_from(matches.value_of("shell").unwrap()).unwrap(),
+        )
+    };
+    assert_eq!("", init_args_from(&["-s", "zsh"]));
+    assert_eq!(" --flags 2", init_args_from(&["-s", "zsh", "--short-path"]));
+    assert_eq!(" --flags 3", init_args_from(&["-s", "bash", "-p", "-u"]));
+    assert_eq!(
+        " --flags 1 --theme 'nord'",
+        init_args_from(&["-s", "bash", "--theme", "nord", "-u"])
+    );
+    assert_eq!(
+        " --status-summary 'dirty,ahead'",
+        init_args_from(&["-s", "bash", "--status-summary", "dirty,ahead"])
+    );
+    assert_eq!(
+        " --word-on '' --word-took 'in'",
+        init_args_from(&["-s", "bash", "--word-on", "", "--word-took", "in"])
+    );
+    assert_eq!(
+        r#" --max-width "\$COLUMNS""#,
+        init_args_from(&["-s", "zsh", "--fit-width"])
+    );
+    assert_eq!(
+        r#" --dirs "\$(( \${#dirstack} + 1 ))""#,
+        init_args_from(&["-s", "zsh", "--dir-stack"])
+    );
+    assert_eq!(
+        r#" --dirs "${#DIRSTACK[@]}""#,
+        init_args_from(&["-s", "bash", "--dir-stack"])
+    );
+    assert_eq!(
+        " --flags 8000000",
+        init_args_from(&["-s", "zsh", "--git-on-right"])
+    );
+    assert_eq!("", init_args_from(&["-s", "bash", "--git-on-right"]));
+    // Forwarding it would show the same directory in every prompt.
+    assert!(app()
+        .get_matches_from_safe(["sprompt", "init", "-s", "bash", "--cwd", "/tmp"])
+        .is_err());
+    assert_eq!(
+        if std::io::stdin().is_terminal() {
+            ""
+        } else {
+            " --non-interactive"
+        },
+        init_args_from(&["-s", "bash", "--show-script"])
+    );
+}
+
+/// The theme picked with `--theme`, with the colors overridden in the config.
+fn load_theme(matches: &ArgMatches, config: &Config) -> Theme {
+    let mut theme = matches
+        .value_of("theme")
+        .and_then(Theme::from_name)
+        .unwrap_or(Theme::DEFAULT);
+    for (role, color) in &config.colors {
+        // Validated when loading the config.
+        let _ = theme.set(role, *color);
+    }
+    theme
+}
+
+/// The secondary prompt, e.g. `… `, shown by the shell while a command spans several lines.
+fn continuation_prompt(
+    matches: &ArgMatches,
+    config: &Config,
+    shell: &Shell,
+    colors: bool,
+) -> String {
+    let theme = load_theme(matches, config);
+    let symbol = if matches.is_present("unicode") {
+        "…"
+    } else {
+        ">"
+    };
+    let (color, reset) = (theme.dim.to_str(shell), Attribute::Reset.to_str(shell));
+    let mut s = match shell {
+        _ if !colors => symbol.to_string(),
+        // Unlike the main prompt, printed by `PROMPT_COMMAND`, `PS2` goes through readline,
+        // which must be told that the escapes take no space.
+        Shell::Bash => format!(r"\[{}\]{}\[{}\]", color, symbol, reset),
+        Shell::Zsh => format!("{}{}{}", color, symbol, reset),
+    };
+    if !matches.is_present("no_trailing_space") {
+        s.push(' ');
+    }
+    s
+}
+
+#[test]
+fn test_continuation_prompt() {
+    let continuation = |args: &[&str], colors| {
+        let matches =
+            app().get_matches_from(["sprompt", "prompt", "--continuation"].iter().chain(args));
+        let matches = matches.subcommand_matches("prompt").unwrap();
+        let shell = Shell::try_from(matches.value_of("shell").unwrap()).unwrap();
+        continuation_prompt(matches, &Config::default(), &shell, colors)
+    };
+    assert_eq!("> ", continuation(&["-s", "bash"], false));
+    assert_eq!(
+        "…",
+        continuation(&["-s", "zsh", "-u", "--no-trailing-space"], false)
+    );
+    assert_eq!(
+        "\\[\u{001b}[30;1m\\]>\\[\u{001b}[0m\\] ",
+        continuation(&["-s", "bash", "--theme", "default"], true)
+    );
+    assert_eq!(
+        "%{\u{001b}[30;1m%}>%{\u{001b}[0m%} ",
+        continuation(&["-s", "zsh"], true)
+    );
+}
+
+/// Whether `cwd` is one of the `excluded` directories or inside one, see `--exclude-git`.
+fn is_git_excluded<'a>(cwd: &Path, excluded: impl IntoIterator<Item = &'a str>) -> bool {
+    excluded.into_iter().any(|path| {
+        let path = config::expand_tilde(path);
+        // The cwd has no symlinks, e.g. `/tmp` is `/private/tmp` on macOS.
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        cwd.starts_with(path)
+    })
+}
+
+#[test]
+fn test_is_git_excluded() {
+    let cwd = Path::new("/mnt/share/a");
+    assert!(is_git_excluded(cwd, vec!["/mnt/share"]));
+    assert!(is_git_excluded(cwd, vec!["/tmp", "/mnt/share/a"]));
+    assert!(!is_git_excluded(cwd, vec!["/mnt/sh"]));
+    assert!(!is_git_excluded(cwd, vec!["/mnt/share/a/b"]));
+    assert!(!is_git_excluded(cwd, Vec::new()));
+}
+
+/// Fabricated data shown by `prompt --preview` instead of the real cwd, repo and last command.
+struct PreviewContext {
+    path: &'static str,
+    /// Name of the repo, the last component of `path`.
+    repo: &'static str,
+    branch: &'static str,
+    elapsed: Duration,
+    exit_code: &'static str,
+}
+
+impl PreviewContext {
+    const SAMPLE: Self = Self {
+        path: "~/projects/sprompt",
+        repo: "sprompt",
+        branch: "main",
+        elapsed: Duration::from_secs(5),
+        exit_code: "0",
+    };
+}
+
+/// The left prompt of `sprompt prompt -s bash <args>`, see `segments_with`.
+#[cfg(test)]
+fn segments(args: &[&str]) -> Vec<Segment> {
+    segments_with(Side::Left, args, &mut Vec::new())
+}
+
+/// The segments of `sprompt prompt -s bash <args>` for `side`, without a config file and, unless
+/// `args` has a `--cwd`, in an empty temporary directory. The state is left alone.
+#[cfg(test)]
+fn segments_with(
+    side: Side,
+    args: &[&str],
+    notes: &mut Vec<(SegmentKind, String)>,
+) -> Vec<Segment> {
+    let dir = tempfile::tempdir().unwrap();
+    let mut all = vec!["sprompt", "prompt", "-s", "bash"];
+    if !args.contains(&"--cwd") {
+        all.extend(&["--cwd", dir.path().to_str().unwrap()]);
+    }
+    all.extend(args);
+    let matches = app().get_matches_from(all);
+    let matches = matches.subcommand_matches("prompt").unwrap();
+    prompt_segments(
+        matches,
+        &Config::default(),
+        side,
+        false,
+        &mut Profiler::new(None),
+        notes,
+    )
+}
+
+#[test]
+fn test_prompt_segments_preview() {
+    let segments = segments(&["--preview", "--lang", "en", "--color", "never"]);
+    assert_eq!(
+        "~/projects/sprompt on main took 5s ::",
+        strip_escapes(&render(segments, false, false))
+    );
+}
+
+#[test]
+fn test_prompt_segments_minimal() {
+    let prompt = |args: &[&str]| {
+        let segments = segments(&[&["--preview"], args].concat());
+        render(segments, true, true)
+    };
+    assert_eq!(
+        "\u{1b}[1m\u{1b}[36m~/projects/sprompt \u{1b}[32m::\u{1b}[0m ",
+        prompt(&["--minimal"])
+    );
+    assert_eq!(
+        "\u{1b}[1m\u{1b}[36;1m~/projects/sprompt \u{1b}[32m❯\u{1b}[0m ",
+        prompt(&[
+            "--minimal",
+            "-u",
+            "--bright-path",
+            "--load",
+            "--mood",
+            "--status-summary",
+            "dirty",
+            "--updates",
+        ])
+    );
+}
+
+#[test]
+fn test_prompt_segments_branch_width() {
+    let prompt = |args: &[&str]| {
+        let segments = segments(&[&["--preview", "--lang", "en"], args].concat());
+        render(segments, false, false)
+    };
+    assert_eq!(
+        "~/projects/sprompt on main   took 5s ::",
+        prompt(&["--branch-min-width", "6"])
+    );
+    assert_eq!(
+        "~/projects/sprompt on ma… took 5s ::",
+        prompt(&["--branch-max-width", "3"])
+    );
+    // The minimum is clamped to the maximum.
+    assert_eq!(
+        "~/projects/sprompt on main took 5s ::",
+        prompt(&["--branch-min-width", "10", "--branch-max-width", "4"])
+    );
+    assert!(app()
+        .get_matches_from_safe(["sprompt", "prompt", "-s", "bash", "--branch-max-width", "0"])
+        .is_err());
+}
+
+#[test]
+fn test_prompt_segments_empty_columns() {
+    // Bash doesn't set `COLUMNS` until after the first prompt.
+    let args = [
+        "-e",
+        "0",
+        "--no-timing",
+        "--adaptive-path",
+        "--columns",
+        "",
+        "--max-width",
+        "",
+    ];
+    let mut segments = segments(&args);
+    assert!(segments.iter().any(|x| x.kind == SegmentKind::Path));
+    let count = segments.len();
+    let matches = app().get_matches_from(["sprompt", "prompt", "-s", "bash"].iter().chain(&args));
+    fit_max_width(matches.subcommand_matches("prompt").unwrap(), &mut segments);
+    assert_eq!(count, segments.len());
+}
+
+#[test]
+fn test_prompt_segments_script() {
+    let prompt = |args: &[&str]| {
+        let segments = segments(&[&["--preview", "--no-timing", "--lang", "en"], args].concat());
+        render(segments, false, false)
+    };
+    assert_eq!("~/projects/sprompt on main ::", prompt(&[]));
+    assert_eq!(
+        "~/projects/sprompt on main [script] ::",
+        prompt(&["--non-interactive"])
+    );
+}
+
+/// Compute the segments of the prompt, in order. When a segment isn't shown for a reason other
+/// than its flag not being passed, the reason is pushed to `notes`, see `sprompt debug`.
+/// `keep_state` is whether the prompt is drawn after a command, the state kept between prompts
+/// (e.g. `--sticky-errors`) is only updated then, otherwise it's only read.
+fn prompt_segments(
+    matches: &ArgMatches,
+    config: &Config,
+    side: Side,
+    keep_state: bool,
+    profiler: &mut Profiler,
+    notes: &mut Vec<(SegmentKind, String)>,
+) -> Vec<Segment> {
+    let preview = if matches.is_present("preview") {
+        Some(PreviewContext::SAMPLE)
+    } else {
+        None
+    };
+    let exit_code = match &preview {
+        Some(x) => x.exit_code,
+        None => matches.value_of("exit_code").unwrap(),
+    };
+    let non_zero_exit_status = exit_code != "0";
+    let shell = &Shell::try_from(matches.value_of("shell").unwrap()).unwrap();
+
+    // No cwd means no git repo and no segments depending on the cwd.
+    let cwd = match matches.value_of("cwd") {
+        _ if preview.is_some() => None,
+        Some(x) => Some(env::current_dir().map_or_else(|_| PathBuf::from(x), |cwd| cwd.join(x))),
+        None => env::current_dir().ok(),
+    };
+    let git_excluded = cwd.as_deref().is_some_and(|cwd| {
+        is_git_excluded(cwd, matches.values_of("exclude_git").into_iter().flatten())
+    });
+    let on_network_fs = !git_excluded
+        && !matches.is_present("no_auto_network_skip")
+        && cwd.as_deref().is_some_and(is_network_fs);
+    // Before opening the repo, libgit2 keeps some files open.
+    let open_files = if matches.is_present("limits") {
+        open_files()
+    } else {
+        None
+    };
+    let git = if git_excluded || on_network_fs {
+        None
+    } else {
+        profiler.time("git_discovery", || cwd.as_deref().and_then(Git::new))
+    };
+    let repo_config = git.as_ref().map(|x| x.prompt_config()).unwrap_or_default();
+
+    let use_unicode = repo_config
+        .unicode
+        .unwrap_or_else(|| matches.is_present("unicode"));
+    let branch_symbol = if use_unicode || matches.is_present("unicode_branch") {
+        " "
+    } else {
+        Default::default()
+    };
+    let separator_symbol = match matches.value_of(if non_zero_exit_status {
+        "separator_error"
+    } else {
+        "separator_success"
+    }) {
+        Some(x) => x,
+        None if use_unicode && !matches.is_present("ascii_separator") => "❯",
+        None => "::",
+    };
+
+    let use_short_path = repo_config
+        .short_path
+        .unwrap_or_else(|| matches.is_present("short_path"));
+    let toplevel = git.as_ref().and_then(|x| x.toplevel());
+    let columns = matches
+        .value_of("columns")
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse::<usize>().unwrap());
+    let adaptive_columns = columns.filter(|_| matches.is_present("adaptive_path"));
+    let mut path_options = PathOptions {
+        always_repo_name: matches.is_present("always_repo_name"),
+        ..Default::default()
+    };
+    if let Some(length) = matches.value_of("path_length") {
+        path_options.length = length.parse().unwrap();
+    }
+    let path = profiler
+        .time("path", || {
+            if let Some(preview) = &preview {
+                let short = if use_short_path {
+                    Some(Some(preview.repo))
+                } else {
+                    None
+                };
+                return Some(shorten_path(preview.path, short, &path_options));
+            }
+            let cwd = cwd.as_deref()?;
+            let fit = match matches.value_of("truncate") {
+                Some("middle") => fit_path_middle,
+                _ => fit_path,
+            };
+            match adaptive_columns {
+                Some(columns) => get_current_path(cwd, None, &path_options)
+                    .map(|x| fit(&x, columns / ADAPTIVE_PATH_FRACTION)),
+                None => get_current_path(
+                    cwd,
+                    if use_short_path { Some(toplevel) } else { None },
+                    &path_options,
+                ),
+            }
+        })
+        .unwrap_or_else(|| "??".into());
+    let quote_paths = matches.is_present("quote_paths");
+    let quote = |path: String| {
+        if quote_paths {
+            quote_path(&path)
+        } else {
+            path
+        }
+    };
+    let path = quote(path);
+    let link = |text: String| match cwd.as_ref().and_then(|x| x.to_str()) {
+        Some(cwd) if matches.is_present("hyperlink_path") => hyperlink(&text, cwd, shell),
+        _ => text,
+    };
+
+    // TODO(agnipau): Windows support.
+    let is_root = preview.is_none() && unsafe { libc::getuid() } == 0;
+
+    // parse can't fail, we checked this using clap.
+    let elapsed = if matches.is_present("no_timing") {
+        None
+    } else if let Some(preview) = &preview {
+        Some(preview.elapsed)
+    } else if let Some(start) = matches.value_of("start_ns") {
+        Some(elapsed_since(
+            parse_clamped_u64(start).unwrap(),
+            SystemTime::now(),
+        ))
+    } else if let Some(ms) = matches.value_of("elapsed_ms") {
+        Some(Duration::from_millis(parse_clamped_u64(ms).unwrap()))
+    } else {
+        let elapsed = parse_clamped_u64(matches.value_of("elapsed_seconds").unwrap()).unwrap();
+        Some(Duration::from_secs(elapsed))
+    };
+
+    let lang = matches
+        .value_of("lang")
+        .map(String::from)
+        .unwrap_or_else(i18n::lang_from_env);
+    let compact = matches.is_present("compact");
+    let word = |name: &str, message: Message| {
+        if compact {
+            return "";
+        }
+        matches
+            .value_of(name)
+            .unwrap_or_else(|| i18n::tr(&lang, message))
+    };
+
+    let branch_min_width = matches
+        .value_of("branch_min_width")
+        .map_or(0, |x| x.parse::<usize>().unwrap());
+    let branch_max_width = matches
+        .value_of("branch_max_width")
+        .map_or(usize::MAX, |x| x.parse::<usize>().unwrap());
+    // Padding past the maximum would make the truncated name wider than it.
+    let branch_min_width = branch_min_width.min(branch_max_width);
+
+    let theme = load_theme(matches, config);
+
+    let mut segments = Vec::new();
+    let root = root_indicator(matches, is_root);
+    match root {
+        Some(RootIndicator::Prefix(symbol)) => segments.push(Segment::new(
+            SegmentKind::Root,
+            format!(
+                "{}{}{}{} {}",
+                Attribute::Bold.to_str(shell),
+                theme.root.to_str(shell),
+                symbol.unwrap_or_else(|| i18n::tr(&lang, Message::Root)),
+                Attribute::Reset.to_str(shell),
+                // A symbol reads fine right before the path.
+                if symbol.is_some() {
+                    String::new()
+                } else {
+                    connector(word("word_in", Message::In))
+                },
+            ),
+        )),
+        Some(RootIndicator::Separator(_)) => notes.push((
+            SegmentKind::Root,
+            "shown as the separator, see --root-position".into(),
+        )),
+        None => notes.push((SegmentKind::Root, "not running as root".into())),
+    }
+    let readonly = cwd.as_ref().and_then(|x| is_readonly(x)) == Some(true);
+    let mut path_color = if matches.is_present("constant_path_color") {
+        theme.path
+    } else {
+        path_color(&theme, readonly, use_short_path && git.is_none())
+    };
+    path_color.bright |= matches.is_present("bright_path");
+    let path_style = format!(
+        "{}{}",
+        Attribute::Bold.to_str(shell),
+        path_color.to_str(shell),
+    );
+    let repo_depth = git
+        .as_ref()
+        .filter(|_| matches.is_present("dim_outside_repo") && !use_short_path)
+        .and_then(|x| x.cwd_depth(cwd.as_deref()?));
+    let styled_path = match repo_depth {
+        Some(depth) => {
+            let dim_style = format!(
+                "{}{}",
+                Attribute::Reset.to_str(shell),
+                theme.dim.to_str(shell)
+            );
+            dim_outside_repo(&path, depth, &dim_style, &path_style)
+        }
+        None => format!("{}{}", path_style, path),
+    };
+    segments.push(Segment {
+        compact: if use_short_path {
+            None
+        } else {
+            cwd.as_deref()
+                .and_then(|cwd| get_current_path(cwd, Some(toplevel), &path_options))
+                .map(|x| format!("{}{} ", path_style, link(quote(x))))
+        },
+        ..Segment::new(SegmentKind::Path, format!("{} ", link(styled_path)))
+    });
+    if matches.is_present("minimal") {
+        for kind in SegmentKind::ALL.iter().filter(|x| x.priority() != u8::MAX) {
+            notes.push((*kind, "left out by --minimal".into()));
+        }
+        segments.push(separator_segment(
+            matches,
+            shell,
+            &theme,
+            config,
+            exit_code,
+            root,
+            separator_symbol,
+        ));
+        if side == Side::Right {
+            segments.clear();
+        }
+        return segments;
+    }
+    if config.markers.is_empty() {
+        notes.push((SegmentKind::Marker, "no markers in the config file".into()));
+    } else if let Some(marker) = cwd.as_ref().and_then(|x| config.marker(x)) {
+        segments.push(Segment::new(
+            SegmentKind::Marker,
+            format!("{}{} ", Attribute::Reset.to_str(shell), marker),
+        ));
+    }
+    if git_excluded {
+        notes.push((SegmentKind::Branch, "excluded by --exclude-git".into()));
+    } else if on_network_fs {
+        notes.push((
+            SegmentKind::Branch,
+            "on a network filesystem, see --no-auto-network-skip".into(),
+        ));
+    } else if git.is_none() {
+        notes.push((SegmentKind::Branch, "not in a git repository".into()));
+    } else if repo_config.hidden == Some(true) {
+        notes.push((
+            SegmentKind::Branch,
+            "hidden by sprompt.hidden in the git config".into(),
+        ));
+    }
+    let git = git.filter(|_| !repo_config.hidden.unwrap_or(false));
+    let branch = match &preview {
+        Some(x) => Some(x.branch.into()),
+        None => profiler.time("branch", || git.as_ref().and_then(|x| x.branch())),
+    };
+    if let Some(branch) = branch {
+        let branch_symbol = if matches.is_present("branch_symbols") {
+            let custom = matches
+                .values_of("branch_symbols")
+                .into_iter()
+                .flatten()
+                .filter_map(|x| x.split_once('='))
+                .collect::<Vec<_>>();
+            let detached = git.as_ref().is_some_and(|x| x.head_detached());
+            branch_type_symbol(&branch, detached, &custom)
+                .map(|x| format!("{} ", x))
+                .unwrap_or_else(|| branch_symbol.into())
+        } else {
+            branch_symbol.into()
+        };
+        let render_branch = |connector: &str| {
+            format!(
+                "{}{}{}{}{}{} ",
+                Attribute::Reset.to_str(shell),
+                connector,
+                Attribute::Bold.to_str(shell),
+                theme.branch.color.to_str(
+                    theme.branch.bright || matches.is_present("bright_branch"),
+                    shell
+                ),
+                branch_symbol,
+                pad_to_width(
+                    &truncate_to_width(&branch, branch_max_width),
+                    branch_min_width
+                )
+            )
+        };
+        let on = connector(word("word_on", Message::On));
+        // Without the connector when space is tight, see `fit_segments`.
+        let compact = if on.is_empty() {
+            None
+        } else {
+            Some(render_branch(""))
+        };
+        segments.push(Segment {
+            compact,
+            ..Segment::new(SegmentKind::Branch, render_branch(&on))
+        });
+        if matches.is_present("warn_default_branch")
+            && git
+                .as_ref()
+                .and_then(|x| try_segment("default_branch", || x.is_default_branch(&branch)))
+                == Some(true)
+        {
+            segments.push(Segment::new(
+                SegmentKind::DefaultBranch,
+                format!(
+                    "{}{} ",
+                    theme.warning.to_str(shell),
+                    if use_unicode { "⚠" } else { "!" }
+                ),
+            ));
+        }
+        if matches.is_present("operation") {
+            if let Some(operation) = git
+                .as_ref()
+                .and_then(|x| try_segment("operation", || x.operation()))
+                .flatten()
+            {
+                segments.push(Segment::new(
+                    SegmentKind::Operation,
+                    format!("{}{} ", theme.error.to_str(shell), operation),
+                ));
+            }
+        }
+        if matches.is_present("sparse")
+            && git
+                .as_ref()
+                .and_then(|x| try_segment("sparse", || x.is_sparse()))
+                == Some(true)
+        {
+            segments.push(Segment::new(
+                SegmentKind::Sparse,
+                format!("{}sparse ", theme.environment.to_str(shell)),
+            ));
+        }
+        if matches.is_present("warn_hooks")
+            && git
+                .as_ref()
+                .and_then(|x| try_segment("hooks", || x.has_hooks()))
+                == Some(true)
+        {
+            segments.push(Segment::new(
+                SegmentKind::Hooks,
+                format!(
+                    "{}{} ",
+                    theme.warning.to_str(shell),
+                    if use_unicode { "⚙" } else { "hooks" }
+                ),
+            ));
+        }
+        if matches.is_present("worktrees") {
+            let count = git
+                .as_ref()
+                .and_then(|x| try_segment("worktrees", || x.worktree_count()));
+            if let Some(count) = count.filter(|x| *x > 1) {
+                segments.push(Segment::new(
+                    SegmentKind::Worktrees,
+                    format!("{}wt:{} ", theme.info.to_str(shell), count),
+                ));
+            }
+        }
+        // Not even computed when they go to the right prompt, the status can be slow.
+        let git = if side == Side::Left && matches.is_present("git_on_right") {
+            for kind in SegmentKind::ALL
+                .iter()
+                .filter(|x| x.on_right() && x.arg().is_some_and(|arg| matches.is_present(arg)))
+            {
+                notes.push((*kind, "on the right prompt, see --git-on-right".into()));
+            }
+            None
+        } else {
+            git.as_ref()
+        };
+        const DIRTY_KINDS: [SegmentKind; 4] = [
+            SegmentKind::Status,
+            SegmentKind::DetailedStatus,
+            SegmentKind::TodoCount,
+            SegmentKind::Submodules,
+        ];
+        let dirty_git = if git.is_some() && repo_config.show_dirty == Some(false) {
+            for kind in DIRTY_KINDS
+                .iter()
+                .filter(|x| x.arg().is_some_and(|arg| matches.is_present(arg)))
+            {
+                notes.push((
+                    *kind,
+                    "hidden by sprompt.showDirty in the git config".into(),
+                ));
+            }
+            None
+        } else {
+            git
+        };
+        if matches.is_present("show_local")
+            && git
+                .as_ref()
+                .and_then(|x| try_segment("local", || x.has_remote()))
+                == Some(false)
+        {
+            segments.push(Segment::new(
+                SegmentKind::Local,
+                format!("{}(local) ", theme.dim.to_str(shell)),
+            ));
+        }
+        if matches.is_present("signed")
+            && git
+                .as_ref()
+                .and_then(|x| try_segment("signed", || x.head_is_signed()))
+                == Some(true)
+        {
+            segments.push(Segment::new(
+                SegmentKind::Signed,
+                format!("{}🔏 ", theme.signed.to_str(shell)),
+            ));
+        }
+        if matches.is_present("git_identity") {
+            if let Some(identity) = git
+                .as_ref()
+                .and_then(|x| try_segment("git_identity", || x.identity_override()))
+                .flatten()
+            {
+                segments.push(Segment::new(
+                    SegmentKind::GitIdentity,
+                    format!("{}{} ", theme.info.to_str(shell), identity),
+                ));
+            }
+        }
+        if let Some(parts) = status_parts(matches) {
+            if let Some(summary) =
+                dirty_git.and_then(|x| try_segment("status", || x.status_summary()))
+            {
+                if let Some(text) = summary.render(&parts, use_unicode, &theme, shell) {
+                    segments.push(Segment::new(SegmentKind::Status, text));
+                }
+            }
+        }
+        if matches.is_present("detailed_status") {
+            if let Some(text) = dirty_git
+                .and_then(|x| try_segment("detailed_status", || x.index_status()))
+                .and_then(|x| x.render(use_unicode, &theme, shell))
+            {
+                segments.push(Segment::new(SegmentKind::DetailedStatus, text));
+            }
+        }
+        if matches.is_present("todo_count") {
+            let count = dirty_git.and_then(|x| try_segment("todo_count", || x.todo_count()));
+            if let Some(count) = count.filter(|x| *x > 0) {
+                segments.push(Segment::new(
+                    SegmentKind::TodoCount,
+                    format!("{}TODO+{} ", theme.warning.to_str(shell), count),
+                ));
+            }
+        }
+        if let Some(remote) = matches.value_of("compare_remote") {
+            let divergences = git
+                .as_ref()
+                .and_then(|x| try_segment("divergence", || x.divergences(remote)))
+                .unwrap_or_default();
+            if let Some(text) = render_divergences(&divergences, use_unicode, &theme, shell) {
+                segments.push(Segment::new(SegmentKind::Divergence, text));
+            }
+        }
+        if matches.is_present("incoming") {
+            if let Some(incoming) = git
+                .as_ref()
+                .and_then(|x| try_segment("incoming", || x.incoming()))
+                .flatten()
+            {
+                let last_fetch = git.as_ref().and_then(|x| x.last_fetch(SystemTime::now()));
+                segments.push(Segment::new(
+                    SegmentKind::Incoming,
+                    render_incoming(incoming, last_fetch, use_unicode, &theme, shell),
+                ));
+            }
+        }
+        if matches.is_present("submodules")
+            && dirty_git.and_then(|x| try_segment("submodules", || x.submodules_dirty()))
+                == Some(true)
+        {
+            segments.push(Segment::new(
+                SegmentKind::Submodules,
+                format!(
+                    "{}{} ",
+                    theme.warning.to_str(shell),
+                    if use_unicode { "⊟" } else { "[sub]" }
+                ),
+            ));
+        }
+        if matches.is_present("commit_count") {
+            if let Some(count) = git
+                .as_ref()
+                .and_then(|x| try_segment("commit_count", || x.cached_commit_count()))
+                .flatten()
+            {
+                segments.push(Segment::new(
+                    SegmentKind::CommitCount,
+                    format!(
+                        "{}{}{} ",
+                        theme.info.to_str(shell),
+                        if use_unicode { "⎇" } else { "#" },
+                        format_commit_count(count)
+                    ),
+                ));
+            }
+        }
+        if matches.is_present("head_age") {
+            if let Some(age) = git
+                .as_ref()
+                .and_then(|x| try_segment("head_age", || x.head_age(SystemTime::now())))
+                .flatten()
+            {
+                segments.push(Segment::new(
+                    SegmentKind::HeadAge,
+                    format!(
+                        "{}{}{} ",
+                        theme.info.to_str(shell),
+                        if use_unicode { "⏱ " } else { "" },
+                        humanize_relative(&age, "ago")
+                    ),
+                ));
+            }
+        }
+        // TODO(agnipau): Checking for git dirty state in a decently performant way in big repos
+        // (like UnrealEngine) is quite difficult.
+    }
+    if matches.is_present("toolchains") {
+        if let Some(cwd) = &cwd {
+            for toolchain in Toolchain::ALL.iter() {
+                if let Some(version) = toolchain.version(cwd) {
+                    segments.push(Segment::new(
+                        SegmentKind::Toolchain,
+                        format!(
+                            "{}{}{} ",
+                            theme
+                                .toolchain
+                                .unwrap_or(ThemeColor::normal(toolchain.color()))
+                                .to_str(shell),
+                            toolchain.symbol(use_unicode),
+                            version
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+    if matches.is_present("nix") {
+        let label = nix_shell_label(
+            env::var("IN_NIX_SHELL").ok().as_deref(),
+            env::var("name").ok().as_deref(),
+        );
+        if let Some(label) = label {
+            segments.push(Segment::new(
+                SegmentKind::Nix,
+                format!(
+                    "{}{}{} ",
+                    theme.environment.to_str(shell),
+                    if use_unicode { "❄ " } else { "nix " },
+                    label
+                ),
+            ));
+        }
+    }
+    if matches.is_present("conda") {
+        let env = conda_env(
+            env::var("CONDA_DEFAULT_ENV").ok().as_deref(),
+            env::var("CONDA_PROMPT_MODIFIER").ok().as_deref(),
+            matches.is_present("conda_show_base"),
+        );
+        if let Some(env) = env {
+            segments.push(Segment::new(
+                SegmentKind::Conda,
+                format!("{}({}) ", theme.environment.to_str(shell), env),
+            ));
+        }
+    }
+    if matches.is_present("direnv") {
+        let status = cwd
+            .as_deref()
+            .and_then(|x| direnv_status(x, env::var_os("DIRENV_DIR").as_deref()));
+        if let Some(status) = status {
+            let color = match status {
+                Direnv::Loaded => theme.success,
+                Direnv::Blocked => theme.warning,
+            };
+            segments.push(Segment::new(
+                SegmentKind::Direnv,
+                format!("{}direnv ", color.to_str(shell)),
+            ));
+        }
+    }
+    if matches.is_present("ssh_agent") {
+        if let Some(agent) = ssh_agent(SystemTime::now()) {
+            let color = match agent {
+                SshAgent::Loaded => theme.success,
+                SshAgent::Empty => theme.dim,
+            };
+            segments.push(Segment::new(
+                SegmentKind::SshAgent,
+                format!(
+                    "{}{} ",
+                    color.to_str(shell),
+                    if use_unicode { "🔑" } else { "ssh" }
+                ),
+            ));
+        } else {
+            notes.push((SegmentKind::SshAgent, "no ssh agent".into()));
+        }
+    }
+    if let Some(prefix) = matches.value_of("env_flags") {
+        // Unlike `env::vars`, doesn't panic on variables that aren't unicode.
+        let vars = env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        });
+        let flags = env_flags(vars, prefix);
+        if flags.is_empty() {
+            notes.push((
+                SegmentKind::EnvFlags,
+                format!("no true variable starting with {}", prefix),
+            ));
+        } else {
+            segments.push(Segment::new(
+                SegmentKind::EnvFlags,
+                format!(
+                    "{}{}{} ",
+                    theme.environment.to_str(shell),
+                    if use_unicode { "⚑ " } else { "flags " },
+                    render_env_flags(&flags)
+                ),
+            ));
+        }
+    }
+    let duration_format = matches
+        .value_of("duration_format")
+        .map_or(DurationFormat::Auto, |x| {
+            DurationFormat::try_from(x).unwrap()
+        });
+    match elapsed {
+        Some(elapsed) if elapsed >= MIN_CMD_EXEC_TIME => {
+            let icon = if matches.is_present("time_icon") {
+                let bands = matches
+                    .value_of("time_icon_bands")
+                    .and_then(parse_time_icon_bands)
+                    .unwrap_or(TIME_ICON_BANDS);
+                connector(time_icon(elapsed, bands, use_unicode))
+            } else {
+                String::new()
+            };
+            let mut text = format!(
+                "{}{}{}{} ",
+                theme.warning.to_str(shell),
+                icon,
+                connector(word("word_took", Message::Took)),
+                duration_format.format(&elapsed),
+            );
+            // Measured by the caller, if at all, sprompt can't see the command.
+            if let Some(kb) = matches.value_of("last_maxrss") {
+                let _ = write!(
+                    &mut text,
+                    "{} ",
+                    humanize_kb(parse_clamped_u64(kb).unwrap())
+                );
+            }
+            if let Some(cpu) = matches.value_of("last_cpu") {
+                let _ = write!(&mut text, "{}% ", cpu);
+            }
+            segments.push(Segment::new(SegmentKind::Timing, text));
+        }
+        Some(elapsed) => notes.push((
+            SegmentKind::Timing,
+            format!(
+                "elapsed {}ms < min {}s",
+                elapsed.as_millis(),
+                MIN_CMD_EXEC_TIME.as_secs()
+            ),
+        )),
+        None => notes.push((SegmentKind::Timing, "--no-timing".into())),
+    }
+    // The preview shows a sample, unrelated to the state of the real prompt.
+    let read_state = preview.is_none();
+    let keep_state = keep_state && read_state;
+    if matches.is_present("sticky_errors") && read_state {
+        let sticky = previous_failed();
+        if keep_state {
+            record_failed(non_zero_exit_status);
+        }
+        if sticky {
+            segments.push(Segment::new(
+                SegmentKind::StickyError,
+                format!(
+                    "{}{} ",
+                    theme.error.to_str(shell),
+                    if use_unicode { "✘" } else { "x" }
+                ),
+            ));
+        }
+    }
+    if matches.is_present("mood") {
+        let streak = if read_state {
+            next_streak(stored_streak(), non_zero_exit_status)
+        } else {
+            HAPPY_STREAK
+        };
+        if keep_state {
+            record_streak(streak);
+        }
+        if let Some(mood) = mood_symbol(streak, use_unicode) {
+            segments.push(Segment::new(
+                SegmentKind::Mood,
+                format!("{}{} ", Attribute::Reset.to_str(shell), mood),
+            ));
+        }
+    }
+    if matches.is_present("show_signals") {
+        let exit_code = exit_code.parse::<i32>();
+        if let Some((code, name)) = exit_code
+            .ok()
+            .and_then(|x| signal_name(x).map(|name| (x, name)))
+        {
+            segments.push(Segment::new(
+                SegmentKind::Signal,
+                format!("{}{}({}) ", theme.error.to_str(shell), name, code),
+            ));
+        }
+    }
+    if matches.is_present("show_readonly") && readonly {
+        segments.push(Segment::new(
+            SegmentKind::Readonly,
+            format!(
+                "{}{} ",
+                theme.error.to_str(shell),
+                if use_unicode { "🔒" } else { "ro" }
+            ),
+        ));
+    }
+    if matches.is_present("load") {
+        if let Some(load) = load_average().filter(|x| is_high_load(*x, num_cpus::get())) {
+            segments.push(Segment::new(
+                SegmentKind::Load,
+                format!("{}load {:.2} ", theme.error.to_str(shell), load),
+            ));
+        }
+    }
+    if let Some((open, limit)) = open_files.filter(|(open, limit)| is_near_limit(*open, *limit)) {
+        segments.push(Segment::new(
+            SegmentKind::Limits,
+            format!(
+                "{}{}{}/{} ",
+                theme.warning.to_str(shell),
+                if use_unicode { "📂 " } else { "files " },
+                open,
+                limit
+            ),
+        ));
+    }
+    if matches.is_present("diskspace") {
+        let threshold = matches
+            .value_of("diskspace")
+            .map_or(LOW_DISK_SPACE_PERCENT, |x| x.parse().unwrap());
+        if let Some(free) = cwd
+            .as_deref()
+            .and_then(free_space_percent)
+            .filter(|x| *x < threshold)
+        {
+            segments.push(Segment::new(
+                SegmentKind::DiskSpace,
+                format!(
+                    "{}{}{:.0}% ",
+                    theme.error.to_str(shell),
+                    if use_unicode { "💾 " } else { "disk " },
+                    free
+                ),
+            ));
+        }
+    }
+    if matches.is_present("updates") {
+        if let Some(count) = pending_updates() {
+            segments.push(Segment::new(
+                SegmentKind::Updates,
+                format!(
+                    "{}{}{} ",
+                    theme.warning.to_str(shell),
+                    if use_unicode { "⬆" } else { "updates " },
+                    count
+                ),
+            ));
+        }
+    }
+    if let Some(depth) = matches
+        .value_of("dirs")
+        .map(|x| x.parse::<usize>().unwrap())
+    {
+        if depth > 1 {
+            segments.push(Segment::new(
+                SegmentKind::Dirs,
+                format!(
+                    "{}{}{} ",
+                    theme.info.to_str(shell),
+                    if use_unicode { "⚞" } else { "dirs " },
+                    depth
+                ),
+            ));
+        } else {
+            notes.push((SegmentKind::Dirs, "the directory stack is empty".into()));
+        }
+    }
+    if matches.is_present("non_interactive") {
+        segments.push(Segment::new(
+            SegmentKind::Script,
+            format!("{}[script] ", theme.dim.to_str(shell)),
+        ));
+    }
+    segments.push(separator_segment(
+        matches,
+        shell,
+        &theme,
+        config,
+        exit_code,
+        root,
+        separator_symbol,
+    ));
+
+    if side == Side::Right {
+        segments.retain(|x| x.kind.on_right());
+    }
+    for segment in segments.iter_mut() {
+        if let Some(priority) = config.priority(segment.kind.name()) {
+            segment.priority = priority;
+        }
+    }
+    segments
+}
+
+/// How running as root is shown, see `--root-position`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RootIndicator<'a> {
+    /// A segment before the path, with `--root-symbol` if given, otherwise the word for root.
+    Prefix(Option<&'a str>),
+    /// This symbol in place of the separator.
+    Separator(&'a str),
+}
+
+/// How to show that sprompt runs as root, `None` if it doesn't.
+fn root_indicator<'a>(matches: &'a ArgMatches, is_root: bool) -> Option<RootIndicator<'a>> {
+    if !is_root {
+        return None;
+    }
+    let symbol = matches.value_of("root_symbol");
+    Some(match matches.value_of("root_position") {
+        // The traditional prompt character of root.
+        Some("separator") => RootIndicator::Separator(symbol.unwrap_or("#")),
+        _ => RootIndicator::Prefix(symbol),
+    })
+}
+
+#[test]
+fn test_root_indicator() {
+    let root_indicator_from = |args: &[&str], is_root: bool| {
+        let matches = app().get_matches_from(
+            ["sprompt", "prompt", "-s", "bash", "-e", "0", "--no-timing"]
+                .iter()
+                .chain(args),
+        );
+        let matches = matches.subcommand_matches("prompt").unwrap();
+        root_indicator(matches, is_root).map(|x| format!("{:?}", x))
+    };
+    assert_eq!(None, root_indicator_from(&["--root-symbol", "#"], false));
+    assert_eq!(Some("Prefix(None)".into()), root_indicator_from(&[], true));
+    assert_eq!(
+        Some(r#"Prefix(Some("⚡"))"#.into()),
+        root_indicator_from(&["--root-symbol", "⚡", "--root-position", "prefix"], true)
+    );
+    assert_eq!(
+        Some(r##"Separator("#")"##.into()),
+        root_indicator_from(&["--root-position", "separator"], true)
+    );
+    assert_eq!(
+        Some(r#"Separator("⚡")"#.into()),
+        root_indicator_from(
+            &["--root-position", "separator", "--root-symbol", "⚡"],
+            true
+        )
+    );
+}
+
+/// The separator, colored by the exit code. `separator_symbol` is the default one, the config
+/// and `--root-position` can replace it.
+fn separator_segment(
+    matches: &ArgMatches,
+    shell: &Shell,
+    theme: &Theme,
+    config: &Config,
+    exit_code: &str,
+    root: Option<RootIndicator>,
+    separator_symbol: &str,
+) -> Segment {
+    let exit_code_style = exit_code
+        .parse::<i32>()
+        .ok()
+        .and_then(|x| config.exit_code(x));
+    // Both shells report a suspended command as killed by `SIGTSTP`.
+    let suspended = matches.is_present("show_suspended")
+        && exit_code.parse().ok().and_then(signal_name) == Some("SIGTSTP");
+    let separator_color = match exit_code_style.and_then(|x| x.color) {
+        Some(x) => x,
+        None if suspended => theme.suspended,
+        None if exit_code != "0" => theme.error,
+        None => theme.success,
+    };
+    // Being root matters more than the exit code.
+    let separator = match root {
+        Some(RootIndicator::Separator(symbol)) => {
+            format!("{}{}", Attribute::Bold.to_str(shell), symbol)
+        }
+        _ => exit_code_style
+            .and_then(|x| x.symbol.as_deref())
+            .unwrap_or(separator_symbol)
+            .into(),
+    };
+    Segment::new(
+        SegmentKind::Separator,
+        format!(
+            "{}{}{}",
+            separator_color.to_str(shell),
+            separator,
+            Attribute::Reset.to_str(shell),
+        ),
+    )
+}
+
+/// Fit the segments in `--max-width`, if given.
+fn fit_max_width(matches: &ArgMatches, segments: &mut Vec<Segment>) {
+    if let Some(max_width) = matches.value_of("max_width").filter(|x| !x.is_empty()) {
+        // The trailing space takes a column too.
+        let max_width = max_width.parse::<usize>().unwrap();
+        let max_width = if !matches.is_present("no_trailing_space") {
+            max_width.saturating_sub(1)
+        } else {
+            max_width
+        };
+        fit_segments(segments, max_width);
+    }
+}
+
+/// One line for each segment, saying whether it's shown and its raw text, or why it isn't.
+/// `computed` are the segments before fitting them in the width of the terminal.
+fn debug_report(
+    matches: &ArgMatches,
+    computed: &[(SegmentKind, String)],
+    shown: &[Segment],
+    notes: &[(SegmentKind, String)],
+) -> String {
+    let mut report = String::new();
+    for kind in SegmentKind::ALL.iter() {
+        let name = kind.name();
+        let mut found = false;
+        for (_, text) in computed.iter().filter(|(x, _)| x == kind) {
+            found = true;
+            let same_kind = |x: &&Segment| x.kind == *kind;
+            let _ = match shown
+                .iter()
+                .filter(same_kind)
+                .find(|x| x.text == *text)
+                .or_else(|| shown.iter().find(same_kind))
+            {
+                Some(x) if x.text == *text => {
+                    writeln!(&mut report, "{:<12} shown: {:?}", name, text)
+                }
+                // Only the path and the branch have a compact variant.
+                Some(x) if matches!(x.kind, SegmentKind::Path | SegmentKind::Branch) => writeln!(
+                    &mut report,
+                    "{:<12} shortened to fit --max-width: {:?}",
+                    name, x.text
+                ),
+                _ => writeln!(
+                    &mut report,
+                    "{:<12} dropped to fit --max-width: {:?}",
+                    name, text
+                ),
+            };
+        }
+        if found {
+            continue;
+        }
+        let reason = match (kind.arg(), notes.iter().find(|(x, _)| x == kind)) {
+            (_, Some((_, note))) => note.clone(),
+            (Some(arg), None) if !matches.is_present(arg) => {
+                let flag = FORWARDED_FLAGS
+                    .iter()
+                    .find(|(x, _)| *x == arg)
+                    .map_or(arg, |x| x.1);
+                format!("not enabled, see {}", flag)
+            }
+            _ => "nothing to show".into(),
+        };
+        let _ = writeln!(&mut report, "{:<12} hidden: {}", name, reason);
+    }
+    report
+}
+
+#[test]
+fn test_prompt_segments_mono_theme() {
+    let segments = segments(&[
+        "-e",
+        "130",
+        "--elapsed-seconds",
+        "5",
+        "--dirs",
+        "3",
+        "--show-signals",
+        "--theme",
+        "mono",
+    ]);
+    assert!(segments.iter().any(|x| x.kind == SegmentKind::Signal));
+    for segment in &segments {
+        // Only white (37) and gray (bright black, 30;1), besides the attributes.
+        for code in segment.text.split('\u{001b}').skip(1) {
+            let code = &code[..code.find('m').unwrap() + 1];
+            assert!(
+                matches!(code, "[37m" | "[37;1m" | "[30;1m" | "[0m" | "[1m"),
+                "{:?} in {:?}",
+                code,
+                segment.text
+            );
+        }
+    }
+}
+
+#[test]
+fn test_prompt_segments_separator() {
+    let separator = |exit_code: &str, args: &[&str]| {
+        let segments = segments(&[&["--no-timing", "-e", exit_code], args].concat());
+        segments.last().unwrap().text.clone()
+    };
+    let symbol = |exit_code: &str, args: &[&str]| strip_escapes(&separator(exit_code, args));
+    let custom = ["--separator-success", "❯", "--separator-error", "✗"];
+    assert_eq!("❯", symbol("0", &custom));
+    assert_eq!("✗", symbol("1", &custom));
+    // Only one of them.
+    assert_eq!("::", symbol("0", &custom[2..]));
+    assert_eq!("❯", symbol("0", &["-u", "--separator-error", "✗"]));
+    assert_eq!("::", symbol("1", &[]));
+    let suspended = Theme::DEFAULT.suspended.to_str(&Shell::Bash);
+    assert!(separator("148", &["--show-suspended"]).starts_with(&*suspended));
+    assert!(!separator("148", &[]).starts_with(&*suspended));
+    assert!(!separator("130", &["--show-suspended"]).starts_with(&*suspended));
+}
+
+#[test]
+fn test_prompt_segments_timing() {
+    let timing = |args: &[&str]| {
+        let segments = segments(&[&["-e", "0", "--lang", "en"], args].concat());
+        segments
+            .iter()
+            .find(|x| x.kind == SegmentKind::Timing)
+            .map(|x| strip_escapes(&x.text))
+    };
+    assert_eq!(Some("took 5s ".into()), timing(&["--elapsed-seconds", "5"]));
+    assert_eq!(
+        Some("took 5s 120MB 85% ".into()),
+        timing(&[
+            "--elapsed-seconds",
+            "5",
+            "--last-maxrss",
+            "122880",
+            "--last-cpu",
+            "85"
+        ])
+    );
+    assert_eq!(
+        None,
+        timing(&["--elapsed-seconds", "0", "--last-maxrss", "122880"])
+    );
+    // Accepted by the validator, clamped.
+    assert_eq!(
+        Some("took 10s 17592186044415GB ".into()),
+        timing(&[
+            "--elapsed-seconds",
+            "10",
+            "--last-maxrss",
+            "99999999999999999999999"
+        ])
+    );
+    assert_eq!(
+        Some("~ took 5s ".into()),
+        timing(&["--elapsed-seconds", "5", "--time-icon"])
+    );
+    assert_eq!(
+        Some("🐌 took 5s ".into()),
+        timing(&[
+            "--elapsed-seconds",
+            "5",
+            "-u",
+            "--time-icon",
+            "--time-icon-bands",
+            "1,3"
+        ])
+    );
+}
+
+#[test]
+fn test_prompt_segments_cwd() {
+    let (dir, repo) = testutil::temp_repo();
+    repo.set_head("refs/heads/topic").unwrap();
+    fs::create_dir(dir.path().join("sub")).unwrap();
+    let cwd = dir.path().join("sub");
+    let segments = segments(&[
+        "-e",
+        "0",
+        "--no-timing",
+        "--lang",
+        "en",
+        "--cwd",
+        cwd.to_str().unwrap(),
+    ]);
+    let text = |kind| {
+        let segment = segments.iter().find(|x| x.kind == kind).unwrap();
+        strip_escapes(&segment.text)
+    };
+    assert!(text(SegmentKind::Path).ends_with("/sub "));
+    assert_eq!("on topic ", text(SegmentKind::Branch));
+}
+
+#[test]
+fn test_prompt_segments_exclude_git() {
+    let (dir, _repo) = testutil::temp_repo();
+    fs::create_dir(dir.path().join("sub")).unwrap();
+    let cwd = dir.path().join("sub").canonicalize().unwrap();
+    let mut notes = Vec::new();
+    let segments = segments_with(
+        Side::Left,
+        &[
+            "-e",
+            "0",
+            "--no-timing",
+            "--cwd",
+            cwd.to_str().unwrap(),
+            "--exclude-git",
+            "/nonexistent",
+            "--exclude-git",
+            cwd.to_str().unwrap(),
+        ],
+        &mut notes,
+    );
+    assert!(segments.iter().all(|x| x.kind != SegmentKind::Branch));
+    assert!(notes.contains(&(SegmentKind::Branch, "excluded by --exclude-git".into())));
+}
+
+#[test]
+fn test_prompt_segments_show_dirty() {
+    let (dir, repo) = testutil::temp_repo();
+    testutil::commit(&repo, "a", "a");
+    fs::write(dir.path().join("a"), "changed").unwrap();
+    repo.config()
+        .unwrap()
+        .set_bool("sprompt.showDirty", false)
+        .unwrap();
+    let cwd = dir.path().canonicalize().unwrap();
+    let mut notes = Vec::new();
+    let segments = segments_with(
+        Side::Left,
+        &[
+            "-e",
+            "0",
+            "--no-timing",
+            "--cwd",
+            cwd.to_str().unwrap(),
+            "--status-summary",
+            "--detailed-status",
+        ],
+        &mut notes,
+    );
+    assert!(segments.iter().any(|x| x.kind == SegmentKind::Branch));
+    assert!(segments.iter().all(|x| x.kind != SegmentKind::Status));
+    assert!(notes.contains(&(
+        SegmentKind::DetailedStatus,
+        "hidden by sprompt.showDirty in the git config".into()
+    )));
+}
+
+#[test]
+fn test_prompt_segments_git_on_right() {
+    let (dir, repo) = testutil::temp_repo();
+    testutil::commit(&repo, "a", "a");
+    fs::write(dir.path().join("a"), "changed").unwrap();
+    let cwd = dir.path().canonicalize().unwrap();
+    let args = [
+        "-e",
+        "0",
+        "--no-timing",
+        "--cwd",
+        cwd.to_str().unwrap(),
+        "--detailed-status",
+        "--warn-default-branch",
+        "--git-on-right",
+    ];
+    let mut notes = Vec::new();
+    let left = segments_with(Side::Left, &args, &mut notes);
+    assert!(left.iter().any(|x| x.kind == SegmentKind::Branch));
+    assert!(left.iter().any(|x| x.kind == SegmentKind::DefaultBranch));
+    assert!(left.iter().all(|x| !x.kind.on_right()));
+    assert!(notes.contains(&(
+        SegmentKind::DetailedStatus,
+        "on the right prompt, see --git-on-right".into()
+    )));
+    // Not enabled, so no note.
+    assert!(notes.iter().all(|(x, _)| *x != SegmentKind::Status));
+    let right = segments_with(Side::Right, &args, &mut Vec::new());
+    assert_eq!("+1", render_right(right, &Shell::Bash, false));
+}
+
+#[test]
+fn test_debug_report() {
+    let matches = app().get_matches_from([
+        "sprompt",
+        "debug",
+        "-s",
+        "bash",
+        "-e",
+        "0",
+        "--no-timing",
+        "--nix",
+    ]);
+    let matches = matches.subcommand_matches("debug").unwrap();
+    let computed = vec![
+        (SegmentKind::Path, "~/a/b ".to_string()),
+        (SegmentKind::Load, "load 9.00 ".to_string()),
+        (SegmentKind::Separator, "::".to_string()),
+    ];
+    let shown = vec![
+        Segment::new(SegmentKind::Path, "b ".into()),
+        Segment::new(SegmentKind::Separator, "::".into()),
+    ];
+    let notes = vec![(SegmentKind::Root, "not running as root".to_string())];
+    let report = debug_report(matches, &computed, &shown, &notes);
+    let lines = report.lines().collect::<Vec<_>>();
+    assert_eq!(SegmentKind::ALL.len(), lines.len());
+    let line = |kind: SegmentKind| lines[SegmentKind::ALL.iter().position(|x| *x == kind).unwrap()];
+    assert_eq!(
+        "root         hidden: not running as root",
+        line(SegmentKind::Root)
+    );
+    assert_eq!(
+        r#"path         shortened to fit --max-width: "b ""#,
+        line(SegmentKind::Path)
+    );
+    assert_eq!(
+        "signed       hidden: not enabled, see --signed",
+        line(SegmentKind::Signed)
+    );
+    assert_eq!(
+        "nix          hidden: nothing to show",
+        line(SegmentKind::Nix)
+    );
+    assert_eq!(
+        r#"load         dropped to fit --max-width: "load 9.00 ""#,
+        line(SegmentKind::Load)
+    );
+    assert_eq!(r#"separator    shown: "::""#, line(SegmentKind::Separator));
+}
+
+/// With `--self-time`, add how long sprompt took to compute the segments before the separator,
+/// the last one.
+fn push_self_time(
+    matches: &ArgMatches,
+    config: &Config,
+    shell: &Shell,
+    elapsed: Duration,
+    segments: &mut Vec<Segment>,
+) {
+    if !matches.is_present("self_time") || matches.is_present("minimal") {
+        return;
+    }
+    let mut segment = Segment::new(
+        SegmentKind::SelfTime,
+        format!(
+            "{}[{}] ",
+            load_theme(matches, config).dim.to_str(shell),
+            format_self_time(elapsed)
+        ),
+    );
+    if let Some(priority) = config.priority(segment.kind.name()) {
+        segment.priority = priority;
+    }
+    segments.insert(segments.len().saturating_sub(1), segment);
+}
+
+/// Milliseconds, seconds from 1 up, the render time of a prompt is never much longer.
+fn format_self_time(elapsed: Duration) -> String {
+    if elapsed < Duration::from_secs(1) {
+        format!("{}ms", elapsed.as_millis())
+    } else {
+        format!("{:.1}s", elapsed.as_secs_f64())
+    }
+}
+
+#[test]
+fn test_format_self_time() {
+    assert_eq!("0ms", format_self_time(Duration::from_micros(300)));
+    assert_eq!("3ms", format_self_time(Duration::from_micros(3900)));
+    assert_eq!("999ms", format_self_time(Duration::from_millis(999)));
+    assert_eq!("1.2s", format_self_time(Duration::from_millis(1240)));
+}
+
+/// Whether to print escapes, see `--color`.
+fn colors_enabled(matches: &ArgMatches) -> bool {
+    matches
+        .value_of("color")
+        .map_or(ColorMode::Auto, |x| ColorMode::try_from(x).unwrap())
+        .enabled(
+            env::var_os("NO_COLOR").as_deref(),
+            env::var("TERM").ok().as_deref(),
+        )
+}
+
+fn main() {
+    let started = Instant::now();
+    let matches = app().get_matches_from(expand_flags(env::args_os()));
+
+    match matches.subcommand() {
+        ("prompt", Some(matches)) => {
+            let shell = &Shell::try_from(matches.value_of("shell").unwrap()).unwrap();
+            let config = Config::load();
+            let colors = colors_enabled(matches);
+            if matches.is_present("continuation") {
+                print!("{}", continuation_prompt(matches, &config, shell, colors));
+                return;
+            }
+
+            let mut profiler = Profiler::new(matches.value_of("profile"));
+            // Both prompts from a single run, git is only opened once.
+            let with_rprompt = matches.is_present("with_rprompt");
+            let side = if with_rprompt { Side::Both } else { Side::Left };
+            let (mut segments, right): (Vec<_>, Vec<_>) =
+                prompt_segments(matches, &config, side, true, &mut profiler, &mut Vec::new())
+                    .into_iter()
+                    .partition(|x| !(with_rprompt && x.kind.on_right()));
+            push_self_time(matches, &config, shell, started.elapsed(), &mut segments);
+
+            fit_max_width(matches, &mut segments);
+
+            let mut prompt = render(segments, !matches.is_present("no_trailing_space"), colors);
+            if with_rprompt {
+                prompt = join_prompts(&prompt, &render_right(right, shell, colors));
+            }
+            if matches.is_present("ps1_escapes") {
+                prompt = mark_escapes(&prompt);
+            }
+            match matches.value_of("write_cache") {
+                Some(path) => write_cache(path, &prompt),
+                None => print!("{}", prompt),
+            }
+
+            profiler.write();
+        }
+        ("rprompt", Some(matches)) => {
+            let shell = &Shell::try_from(matches.value_of("shell").unwrap()).unwrap();
+            let config = Config::load();
+            // Rendered after the left prompt for the same command, which already updated the
+            // state.
+            let segments = prompt_segments(
+                matches,
+                &config,
+                Side::Right,
+                false,
+                &mut Profiler::new(None),
+                &mut Vec::new(),
+            );
+            print!("{}", render_right(segments, shell, colors_enabled(matches)));
+        }
+        ("debug", Some(matches)) => {
+            let shell = &Shell::try_from(matches.value_of("shell").unwrap()).unwrap();
+            let config = Config::load();
+            let mut notes = Vec::new();
+            // Only explains the prompt, the next one must look the same.
+            let mut segments = prompt_segments(
+                matches,
+                &config,
+                Side::Left,
+                false,
+                &mut Profiler::new(None),
+                &mut notes,
+            );
+            push_self_time(matches, &config, shell, started.elapsed(), &mut segments);
+            let computed = segments
+                .iter()
+                .map(|x| (x.kind, x.text.clone()))
+                .collect::<Vec<_>>();
+            fit_max_width(matches, &mut segments);
+            print!("{}", debug_report(matches, &computed, &segments, &notes));
+        }
+        ("init", Some(matches)) => {
+            let code = match matches.value_of("shell") {
+                Some(shell) => {
+                    let shell = Shell::try_from(shell).unwrap();
+
+                    let args = init_args(matches, &shell);
+
+                    let mut opts = InitOptions {
+                        timing: !matches.is_present("no_timing"),
+                        trailing_space: !matches.is_present("no_trailing_space"),
+                        ..Default::default()
+                    };
+                    if let Some(function_name) = matches.value_of("function_name") {
+                        opts.function_name = function_name.into();
+                    }
+                    opts.cache = matches.value_of("cache").map(|x| x.into());
+                    opts.precise_timing = matches.is_present("precise_timing");
+                    opts.ps1 = matches.is_present("ps1");
+                    opts.cache_on_cwd = matches.is_present("cache_on_cwd");
+                    opts.rprompt = matches.is_present("git_on_right");
+                    opts.continuation_args = continuation_args(matches, &shell);
+                    shell.init_code(&args, &opts)
+                }
+                None => universal_init_code(matches),
+            };
+            match matches.value_of("output") {
+                Some(path) => {
+                    if let Err(e) = write_init_code(Path::new(path), &code) {
+                        eprintln!("sprompt: can't write {}: {}", path, e);
+                        std::process::exit(1);
+                    }
+                    println!("{}", path);
+                }
+                None => println!("{}", code),
+            }
+        }
+        ("uninstall", Some(matches)) => {
+            let shell = Shell::try_from(matches.value_of("shell").unwrap()).unwrap();
+            let function_name = matches.value_of("function_name").unwrap_or("_sprompt");
+            println!("{}", shell.uninstall_code(function_name));
+        }
+        ("dump-escapes", Some(matches)) => {
+            let shell = Shell::try_from(matches.value_of("shell").unwrap()).unwrap();
+            print!("{}", dump_escapes(&shell));
+        }
+        _ => unreachable!(),
+    }
+}
+
+struct Git {
+    repo: Repository,
+}
+
+impl Git {
+    fn new(cwd: &Path) -> Option<Self> {
+        let repo = Repository::discover(cwd).ok()?;
+        Some(Self { repo })
+    }
+
+    // https://github.com/starship/starship/blob/d670212a083e9f1e9c9a2313e7ce5e72e908efa7/src/context.rs
+    fn branch(&self) -> Option<String> {
+        let head = match self.repo.head() {
+            Ok(reference) => reference,
+            Err(e) => {
+                return if e.code() == ErrorCode::UnbornBranch {
+                    // HEAD should only be an unborn branch if the repository is fresh, in that case
+                    // read directly from `.git/HEAD`.
+                    let mut head_path = self.repo.path().to_path_buf();
+                    head_path.push("HEAD");
+                    // Get the first line, then last path segment.
+                    fs::read_to_string(&head_path)
+                        .ok()?
+                        .lines()
+                        .next()?
+                        .trim()
+                        .split('/')
+                        .next_back()
+                        .map(|r| r.to_owned())
+                } else {
+                    None
+                };
+            }
+        };
+        let shorthand = head.shorthand();
+        shorthand.map(|x| x.into())
+    }
+
+    fn head_detached(&self) -> bool {
+        self.repo.head_detached().unwrap_or(false)
+    }
+
+    /// Whether the HEAD commit carries a signature. The signature itself isn't verified, that
+    /// would require access to the user's keyring.
+    fn head_is_signed(&self) -> Result<bool, git2::Error> {
+        let oid = match self.repo.head() {
+            Ok(head) => match head.target() {
+                Some(x) => x,
+                None => return Ok(false),
+            },
+            Err(e) if matches!(e.code(), ErrorCode::UnbornBranch | ErrorCode::NotFound) => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        };
+        match self.repo.extract_signature(&oid, None) {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Time since the HEAD commit was committed (not authored, so that rebasing resets it).
+    /// `None` in empty repos.
+    fn head_age(&self, now: SystemTime) -> Result<Option<Duration>, git2::Error> {
+        let commit = match self.repo.head() {
+            Ok(head) => head.peel_to_commit()?,
+            Err(e) if matches!(e.code(), ErrorCode::UnbornBranch | ErrorCode::NotFound) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(commit.time().seconds().max(0) as u64);
+        // Commits from the future, thanks to clock skew, are brand new.
+        Ok(Some(now.duration_since(time).unwrap_or_default()))
+    }
+
+    /// Count dirty files and conflicts with a single pass over the statuses, plus the commits
+    /// the current branch is ahead of its upstream, if any.
+    fn status_summary(&self) -> Result<StatusSummary, git2::Error> {
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(true);
+        let statuses = self.repo.statuses(Some(&mut opts))?;
+        let mut summary = StatusSummary::default();
+        for entry in statuses.iter() {
+            let status = entry.status();
+            if status.is_conflicted() {
+                summary.conflicts += 1;
+            } else if !status.is_ignored() && status != Status::CURRENT {
+                summary.dirty += 1;
+            }
+        }
+        if let Some((ahead, _)) = self.ahead_behind()? {
+            summary.ahead = ahead;
+        }
+        Ok(summary)
+    }
+
+    /// Lines containing `TODO` or `FIXME` added since HEAD, staged or not, untracked files
+    /// included. Only the first `MAX_TODO_LINES` added lines are scanned.
+    fn todo_count(&self) -> Result<usize, git2::Error> {
+        let head = self.repo.head().ok().and_then(|x| x.peel_to_tree().ok());
+        let mut opts = DiffOptions::new();
+        opts.context_lines(0)
+            .include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .show_untracked_content(true);
+        let diff = self
+            .repo
+            .diff_tree_to_workdir_with_index(head.as_ref(), Some(&mut opts))?;
+        let (mut scanned, mut count) = (0, 0);
+        let result = diff.foreach(
+            &mut |_, _| true,
+            None,
+            None,
+            Some(&mut |_, _, line| {
+                if line.origin() == '+' {
+                    scanned += 1;
+                    let content = String::from_utf8_lossy(line.content());
+                    if content.contains("TODO") || content.contains("FIXME") {
+                        count += 1;
+                    }
+                }
+                scanned < MAX_TODO_LINES
+            }),
+        );
+        match result {
+            // Stopping the scan is an error too.
+            Err(e) if scanned < MAX_TODO_LINES => Err(e),
+            _ => Ok(count),
+        }
+    }
+
+    /// Files with changes in the index and in the worktree, untracked files included. A file
+    /// changed in both counts in both.
+    fn index_status(&self) -> Result<IndexStatus, git2::Error> {
+        let staged = Status::INDEX_NEW
+            | Status::INDEX_MODIFIED
+            | Status::INDEX_DELETED
+            | Status::INDEX_RENAMED
+            | Status::INDEX_TYPECHANGE;
+        let unstaged = Status::WT_NEW
+            | Status::WT_MODIFIED
+            | Status::WT_DELETED
+            | Status::WT_RENAMED
+            | Status::WT_TYPECHANGE;
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(true);
+        let mut index_status = IndexStatus::default();
+        for entry in self.repo.statuses(Some(&mut opts))?.iter() {
+            let status = entry.status();
+            if status.intersects(staged) {
+                index_status.staged += 1;
+            }
+            if status.intersects(unstaged) {
+                index_status.unstaged += 1;
+            }
+        }
+        Ok(index_status)
+    }
+
+    /// Whether any submodule is uninitialized or differs from what's recorded in HEAD. Untracked
+    /// files inside submodules are ignored.
+    fn submodules_dirty(&self) -> Result<bool, git2::Error> {
+        let clean = SubmoduleStatus::IN_HEAD
+            | SubmoduleStatus::IN_INDEX
+            | SubmoduleStatus::IN_CONFIG
+            | SubmoduleStatus::IN_WD;
+        for submodule in self.repo.submodules()? {
+            if let Some(name) = submodule.name() {
+                let status = self
+                    .repo
+                    .submodule_status(name, SubmoduleIgnore::Untracked)?;
+                if status.intersects(!clean) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// HEAD if it points to a branch, `None` in empty repos and with a detached HEAD.
+    fn head_branch(&self) -> Result<Option<git2::Reference<'_>>, git2::Error> {
+        match self.repo.head() {
+            Ok(head) if head.is_branch() => Ok(Some(head)),
+            Ok(_) => Ok(None),
+            Err(e) if matches!(e.code(), ErrorCode::UnbornBranch | ErrorCode::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Commits the current branch is ahead and behind of its upstream, `None` without one.
+    fn ahead_behind(&self) -> Result<Option<(usize, usize)>, git2::Error> {
+        let head = match self.head_branch()? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let local = head.target();
+        let upstream = match Branch::wrap(head).upstream() {
+            Ok(x) => x.get().target(),
+            Err(e) if e.code() == ErrorCode::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match (local, upstream) {
+            (Some(local), Some(upstream)) => {
+                self.repo.graph_ahead_behind(local, upstream).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Commits the current branch is ahead and behind of the branch with the same name on
+    /// `remote`, `None` if there's no such branch.
+    fn remote_ahead_behind(&self, remote: &str) -> Result<Option<(usize, usize)>, git2::Error> {
+        let head = match self.head_branch()? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let (local, shorthand) = match (head.target(), head.shorthand()) {
+            (Some(local), Some(shorthand)) => (local, shorthand),
+            _ => return Ok(None),
+        };
+        let name = format!("refs/remotes/{}/{}", remote, shorthand);
+        let other = match self.repo.find_reference(&name) {
+            Ok(x) => x.target(),
+            Err(e) if e.code() == ErrorCode::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match other {
+            Some(other) => self.repo.graph_ahead_behind(local, other).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The operation in progress, e.g. `merge`, followed by the progress for the ones going
+    /// through several commits when git records it, e.g. `rebase 3/10`.
+    fn operation(&self) -> io::Result<Option<String>> {
+        let (name, progress) = match self.repo.state() {
+            RepositoryState::Clean => return Ok(None),
+            RepositoryState::Merge => ("merge", None),
+            RepositoryState::Revert | RepositoryState::RevertSequence => ("revert", None),
+            RepositoryState::CherryPick | RepositoryState::CherryPickSequence => {
+                ("cherry-pick", None)
+            }
+            RepositoryState::Bisect => ("bisect", None),
+            RepositoryState::RebaseInteractive | RepositoryState::RebaseMerge => {
+                ("rebase", Some(("rebase-merge/msgnum", "rebase-merge/end")))
+            }
+            RepositoryState::Rebase | RepositoryState::ApplyMailboxOrRebase => {
+                ("rebase", Some(("rebase-apply/next", "rebase-apply/last")))
+            }
+            RepositoryState::ApplyMailbox => {
+                ("am", Some(("rebase-apply/next", "rebase-apply/last")))
+            }
+        };
+        let progress = match progress {
+            Some((done, total)) => read_progress(self.repo.path(), done, total)?,
+            None => None,
+        };
+        Ok(Some(match progress {
+            Some((done, total)) => format!("{} {}/{}", name, done, total),
+            None => name.into(),
+        }))
+    }
+
+    /// Whether `branch` is the default one: the branch `origin/HEAD` points to, or else
+    /// `init.defaultBranch` from the git config, or else `main` or `master`.
+    fn is_default_branch(&self, branch: &str) -> Result<bool, git2::Error> {
+        match self.repo.find_reference("refs/remotes/origin/HEAD") {
+            Ok(reference) => {
+                return Ok(reference
+                    .symbolic_target()
+                    .and_then(|x| x.strip_prefix("refs/remotes/origin/"))
+                    == Some(branch))
+            }
+            Err(e) if e.code() == ErrorCode::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        match self.repo.config()?.get_string("init.defaultBranch") {
+            Ok(default) => Ok(default == branch),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(branch == "main" || branch == "master"),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether sparse checkout is enabled. `.git/info/sparse-checkout` alone isn't enough, it
+    /// stays around after `git sparse-checkout disable`.
+    fn is_sparse(&self) -> Result<bool, git2::Error> {
+        match self.repo.config()?.get_bool("core.sparseCheckout") {
+            Ok(x) => Ok(x),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The directory of the hooks, `core.hooksPath` if set (relative to the worktree, like for
+    /// git), otherwise `hooks` in the git dir.
+    fn hooks_dir(&self) -> Result<PathBuf, git2::Error> {
+        match self.repo.config()?.get_path("core.hooksPath") {
+            Ok(x) => Ok(self.repo.workdir().unwrap_or(self.repo.path()).join(x)),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(self.repo.path().join("hooks")),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether any hook is installed, see `has_hooks_in`. Not cached, making a hook executable
+    /// doesn't change anything a cache could be keyed on but the hook itself, and reading the
+    /// directory costs about as much as checking it.
+    fn has_hooks(&self) -> Result<bool, git2::Error> {
+        Ok(has_hooks_in(&self.hooks_dir()?))
+    }
+
+    /// Number of worktrees of the repo, the main one included.
+    fn worktree_count(&self) -> Result<usize, git2::Error> {
+        Ok(self.repo.worktrees()?.len() + 1)
+    }
+
+    /// Whether at least one remote is configured.
+    fn has_remote(&self) -> Result<bool, git2::Error> {
+        Ok(!self.repo.remotes()?.is_empty())
+    }
+
+    /// Name of the remote of the upstream of the current branch.
+    fn upstream_remote(&self) -> Option<String> {
+        let head = self.repo.head().ok()?;
+        let remote = self.repo.branch_upstream_remote(head.name()?).ok()?;
+        remote.as_str().map(|x| x.into())
+    }
+
+    /// Commits of the upstream that aren't in the current branch, as of the last fetch. `None`
+    /// without an upstream or when there are none.
+    fn incoming(&self) -> Result<Option<usize>, git2::Error> {
+        Ok(self
+            .ahead_behind()?
+            .map(|(_, behind)| behind)
+            .filter(|x| *x > 0))
+    }
+
+    /// Time since the last fetch, going by the modification time of `FETCH_HEAD`. `None` if
+    /// the repo was never fetched.
+    fn last_fetch(&self, now: SystemTime) -> Option<Duration> {
+        let modified = fs::metadata(self.repo.path().join("FETCH_HEAD"))
+            .and_then(|x| x.modified())
+            .ok()?;
+        Some(now.duration_since(modified).unwrap_or_default())
+    }
+
+    /// Divergence from the upstream and from the current branch on `remote`, labeled with the
+    /// remote names. Missing remotes or refs are skipped.
+    fn divergences(&self, remote: &str) -> Result<Vec<Divergence>, git2::Error> {
+        let mut divergences = Vec::new();
+        let upstream_remote = self.upstream_remote();
+        if let Some(name) = &upstream_remote {
+            if let Some(x) = self.ahead_behind()? {
+                divergences.push((name.clone(), x));
+            }
+        }
+        if upstream_remote.as_deref() != Some(remote) {
+            if let Some(x) = self.remote_ahead_behind(remote)? {
+                divergences.push((remote.into(), x));
+            }
+        }
+        Ok(divergences)
+    }
+
+    /// Number of commits reachable from HEAD, counting up to `MAX_COMMIT_COUNT + 1`. `None` in
+    /// empty repos.
+    fn commit_count(&self) -> Result<Option<usize>, git2::Error> {
+        if self.repo.head().is_err() {
+            return Ok(None);
+        }
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push_head()?;
+        let mut count = 0;
+        for oid in revwalk.take(MAX_COMMIT_COUNT + 1) {
+            oid?;
+            count += 1;
+        }
+        Ok(Some(count))
+    }
+
+    /// `commit_count`, cached by the oid of HEAD since walking the history is slow.
+    fn cached_commit_count(&self) -> Result<Option<usize>, git2::Error> {
+        let oid = match self.repo.head().ok().and_then(|x| x.target()) {
+            Some(x) => x.to_string(),
+            None => return Ok(None),
+        };
+        let state = State::global("commit-count");
+        let cached = state.as_ref().and_then(|x| x.read()).and_then(|x| {
+            let (cached_oid, count) = x.split_once(' ')?;
+            if cached_oid == oid {
+                count.trim().parse().ok()
+            } else {
+                None
+            }
+        });
+        if cached.is_some() {
+            return Ok(cached);
+        }
+        let count = self.commit_count()?;
+        if let (Some(state), Some(count)) = (state, count) {
+            let _ = state.write(&format!("{} {}", oid, count));
+        }
+        Ok(count)
+    }
+
+    /// Read the `[sprompt]` section of the repo's git config (which also includes the global
+    /// and system ones). Missing or invalid keys are left unset.
+    fn prompt_config(&self) -> RepoConfig {
+        let config = match self.repo.config() {
+            Ok(x) => x,
+            Err(_) => return Default::default(),
+        };
+        RepoConfig {
+            hidden: config.get_bool("sprompt.hidden").ok(),
+            short_path: config.get_bool("sprompt.shortPath").ok(),
+            unicode: config.get_bool("sprompt.unicode").ok(),
+            show_dirty: config.get_bool("sprompt.showDirty").ok(),
+        }
+    }
+
+    /// The identity used for commits in this repo when it differs from the global one, see
+    /// `differing_identity`.
+    fn identity_override(&self) -> Result<Option<String>, git2::Error> {
+        let global = git2::Config::open_default()?;
+        Ok(differing_identity(&self.repo.config()?, &global))
+    }
+
+    fn toplevel(&self) -> Option<&str> {
+        self.repo.workdir()?.file_name()?.to_str()
+    }
+
+    /// Number of trailing components of the cwd that are inside the working tree, the repo
+    /// directory included.
+    fn cwd_depth(&self, cwd: &Path) -> Option<usize> {
+        let relative = cwd.strip_prefix(self.repo.workdir()?).ok()?;
+        Some(relative.components().count() + 1)
+    }
+}
+
+#[test]
+fn test_git_branch() {
+    let (_dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    // Unborn branch, read from `.git/HEAD`.
+    assert!(git.branch().is_some());
+    testutil::commit(&git.repo, "a", "a");
+    testutil::checkout_new_branch(&git.repo, "feature/x");
+    assert_eq!(Some("feature/x".into()), git.branch());
+    assert!(!git.head_detached());
+    testutil::detach(&git.repo);
+    assert_eq!(Some("HEAD".into()), git.branch());
+    assert!(git.head_detached());
+    assert!(!git.head_is_signed().unwrap());
+}
+
+#[test]
+fn test_git_status_summary() {
+    let (dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    testutil::commit(&git.repo, "a", "a");
+    let summary = git.status_summary().unwrap();
+    assert_eq!((0, 0), (summary.dirty, summary.conflicts));
+    fs::write(dir.path().join("a"), "changed").unwrap();
+    fs::write(dir.path().join("b"), "untracked").unwrap();
+    assert_eq!(2, git.status_summary().unwrap().dirty);
+}
+
+#[test]
+fn test_git_todo_count() {
+    let (dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    assert_eq!(0, git.todo_count().unwrap());
+    testutil::commit(&git.repo, "a", "// TODO: committed\n");
+    assert_eq!(0, git.todo_count().unwrap());
+    fs::write(
+        dir.path().join("a"),
+        "// TODO: committed\n// TODO: unstaged\nok\n",
+    )
+    .unwrap();
+    assert_eq!(1, git.todo_count().unwrap());
+    fs::write(dir.path().join("b"), "FIXME\nTODO\n").unwrap();
+    let mut index = git.repo.index().unwrap();
+    index.add_path(Path::new("b")).unwrap();
+    index.write().unwrap();
+    assert_eq!(3, git.todo_count().unwrap());
+    fs::create_dir(dir.path().join("new")).unwrap();
+    fs::write(dir.path().join("new/c"), "TODO untracked\n").unwrap();
+    assert_eq!(4, git.todo_count().unwrap());
+    // Removed lines don't count.
+    fs::write(dir.path().join("a"), "ok\n").unwrap();
+    assert_eq!(3, git.todo_count().unwrap());
+    // Capped.
+    let lines = "TODO\n".repeat(MAX_TODO_LINES + 10);
+    fs::write(dir.path().join("new/d"), lines).unwrap();
+    assert!(git.todo_count().unwrap() <= MAX_TODO_LINES);
+}
+
+#[test]
+fn test_git_index_status() {
+    let (dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    assert_eq!(IndexStatus::default(), git.index_status().unwrap());
+    testutil::commit(&git.repo, "a", "a");
+    testutil::commit(&git.repo, "b", "b");
+    // Staged, then changed again.
+    fs::write(dir.path().join("a"), "staged").unwrap();
+    let mut index = git.repo.index().unwrap();
+    index.add_path(Path::new("a")).unwrap();
+    index.write().unwrap();
+    fs::write(dir.path().join("a"), "unstaged").unwrap();
+    // Unstaged only.
+    fs::write(dir.path().join("b"), "unstaged").unwrap();
+    // Untracked.
+    fs::write(dir.path().join("c"), "c").unwrap();
+    assert_eq!(
+        IndexStatus {
+            staged: 1,
+            unstaged: 3
+        },
+        git.index_status().unwrap()
+    );
+}
+
+#[test]
+fn test_git_ahead_behind() {
+    let (_dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    testutil::commit(&git.repo, "a", "a");
+    let main = git.branch().unwrap();
+    testutil::checkout_new_branch(&git.repo, "topic");
+    assert_eq!(None, git.ahead_behind().unwrap());
+    testutil::set_upstream(&git.repo, "topic", &main);
+    assert_eq!(Some((0, 0)), git.ahead_behind().unwrap());
+    testutil::commit(&git.repo, "a", "b");
+    testutil::commit(&git.repo, "a", "c");
+    assert_eq!(Some((2, 0)), git.ahead_behind().unwrap());
+}
+
+/// The step being applied and the number of steps, from the files `done` and `total` in
+/// `git_dir`. `None` if they're missing or don't make sense.
+fn read_progress(git_dir: &Path, done: &str, total: &str) -> io::Result<Option<(u32, u32)>> {
+    let read = |name| match fs::read_to_string(git_dir.join(name)) {
+        Ok(x) => Ok(x.trim().parse::<u32>().ok()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+    Ok(match (read(done)?, read(total)?) {
+        (Some(done), Some(total)) if done <= total => Some((done, total)),
+        _ => None,
+    })
+}
+
+#[test]
+fn test_git_operation() {
+    let (_dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    testutil::commit(&git.repo, "a", "a");
+    assert_eq!(None, git.operation().unwrap());
+    let rebase = git.repo.path().join("rebase-merge");
+    fs::create_dir(&rebase).unwrap();
+    fs::write(rebase.join("interactive"), "").unwrap();
+    assert_eq!(Some("rebase".into()), git.operation().unwrap());
+    fs::write(rebase.join("msgnum"), "3\n").unwrap();
+    fs::write(rebase.join("end"), "10\n").unwrap();
+    assert_eq!(Some("rebase 3/10".into()), git.operation().unwrap());
+    fs::write(rebase.join("end"), "x").unwrap();
+    assert_eq!(Some("rebase".into()), git.operation().unwrap());
+    fs::remove_dir_all(&rebase).unwrap();
+    fs::write(git.repo.path().join("MERGE_HEAD"), "").unwrap();
+    assert_eq!(Some("merge".into()), git.operation().unwrap());
+}
+
+#[test]
+fn test_read_progress() {
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(None, read_progress(dir.path(), "next", "last").unwrap());
+    fs::write(dir.path().join("next"), "2").unwrap();
+    assert_eq!(None, read_progress(dir.path(), "next", "last").unwrap());
+    fs::write(dir.path().join("last"), " 5\n").unwrap();
+    assert_eq!(
+        Some((2, 5)),
+        read_progress(dir.path(), "next", "last").unwrap()
+    );
+    fs::write(dir.path().join("next"), "6").unwrap();
+    assert_eq!(None, read_progress(dir.path(), "next", "last").unwrap());
+    fs::write(dir.path().join("next"), "-1").unwrap();
+    assert_eq!(None, read_progress(dir.path(), "next", "last").unwrap());
+}
+
+#[test]
+fn test_git_is_default_branch() {
+    let (_dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    testutil::commit(&git.repo, "a", "a");
+    assert!(!git.is_default_branch("feature").unwrap());
+    git.repo
+        .config()
+        .unwrap()
+        .set_str("init.defaultBranch", "trunk")
+        .unwrap();
+    assert!(git.is_default_branch("trunk").unwrap());
+    assert!(!git.is_default_branch("main").unwrap());
+    // `origin/HEAD` wins over the config.
+    let head = git.repo.head().unwrap().target().unwrap();
+    git.repo
+        .reference("refs/remotes/origin/develop", head, false, "")
+        .unwrap();
+    git.repo
+        .reference_symbolic(
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/develop",
+            false,
+            "",
+        )
+        .unwrap();
+    assert!(git.is_default_branch("develop").unwrap());
+    assert!(!git.is_default_branch("trunk").unwrap());
+}
+
+#[test]
+fn test_git_is_sparse() {
+    let (_dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    assert!(!git.is_sparse().unwrap());
+    let mut config = git.repo.config().unwrap();
+    config.set_bool("core.sparseCheckout", true).unwrap();
+    assert!(git.is_sparse().unwrap());
+    config.set_str("core.sparseCheckout", "maybe").unwrap();
+    assert!(git.is_sparse().is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_git_has_hooks() {
+    let (dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    let hooks = git.repo.path().join("hooks");
+    fs::create_dir_all(&hooks).unwrap();
+    fs::write(hooks.join("pre-commit.sample"), "#!/bin/sh").unwrap();
+    assert!(!git.has_hooks().unwrap());
+    fs::write(hooks.join("pre-commit"), "#!/bin/sh").unwrap();
+    assert!(!git.has_hooks().unwrap());
+    set_executable(&hooks.join("pre-commit"));
+    assert!(git.has_hooks().unwrap());
+    // Elsewhere, relative to the worktree.
+    let custom = dir.path().join(".githooks");
+    fs::create_dir(&custom).unwrap();
+    let mut config = git.repo.config().unwrap();
+    config.set_str("core.hooksPath", ".githooks").unwrap();
+    assert_eq!(custom, git.hooks_dir().unwrap());
+    assert!(!git.has_hooks().unwrap());
+    config.set_str("core.hooksPath", "missing").unwrap();
+    assert!(!git.has_hooks().unwrap());
+}
+
+#[test]
+fn test_git_worktree_count() {
+    let (_dir, repo) = testutil::temp_repo();
+    testutil::commit(&repo, "a", "a");
+    let git = Git { repo };
+    assert_eq!(1, git.worktree_count().unwrap());
+    let other = tempfile::tempdir().unwrap();
+    let path = other.path().join("linked");
+    git.repo.worktree("linked", &path, None).unwrap();
+    assert_eq!(2, git.worktree_count().unwrap());
+    // The same from the linked one.
+    let linked = Git {
+        repo: Repository::open(&path).unwrap(),
+    };
+    assert_eq!(2, linked.worktree_count().unwrap());
+}
+
+#[test]
+fn test_git_has_remote() {
+    let (_dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    assert!(!git.has_remote().unwrap());
+    git.repo
+        .remote("origin", "https://example.com/repo.git")
+        .unwrap();
+    assert!(git.has_remote().unwrap());
+}
+
+#[test]
+fn test_git_commit_count() {
+    let (_dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    assert_eq!(None, git.commit_count().unwrap());
+    testutil::commit(&git.repo, "a", "a");
+    testutil::commit(&git.repo, "a", "b");
+    assert_eq!(Some(2), git.commit_count().unwrap());
+}
+
+#[test]
+fn test_git_incoming() {
+    let (_dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    assert_eq!(None, git.incoming().unwrap());
+    testutil::commit(&git.repo, "a", "a");
+    let main = git.branch().unwrap();
+    testutil::checkout_new_branch(&git.repo, "topic");
+    testutil::set_upstream(&git.repo, "topic", &main);
+    assert_eq!(None, git.incoming().unwrap());
+    git.repo.set_head(&format!("refs/heads/{}", main)).unwrap();
+    testutil::commit(&git.repo, "a", "b");
+    testutil::commit(&git.repo, "a", "c");
+    git.repo.set_head("refs/heads/topic").unwrap();
+    assert_eq!(Some(2), git.incoming().unwrap());
+    assert_eq!(None, git.last_fetch(SystemTime::now()));
+}
+
+#[test]
+fn test_git_head_age() {
+    let (_dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    assert_eq!(None, git.head_age(SystemTime::now()).unwrap());
+    let oid = testutil::commit(&git.repo, "a", "a");
+    let time = git.repo.find_commit(oid).unwrap().time().seconds() as u64;
+    let now = UNIX_EPOCH + Duration::from_secs(time + 60 * 60 * 24 * 3);
+    assert_eq!(
+        Some(Duration::from_secs(60 * 60 * 24 * 3)),
+        git.head_age(now).unwrap()
+    );
+    assert_eq!(Some(Duration::ZERO), git.head_age(UNIX_EPOCH).unwrap());
+}
+
+#[test]
+fn test_git_divergences() {
+    let (_dir, repo) = testutil::temp_repo();
+    let git = Git { repo };
+    let base = testutil::commit(&git.repo, "a", "a");
+    let branch = git.branch().unwrap();
+    assert!(git.divergences("upstream").unwrap().is_empty());
+    git.repo
+        .reference(
+            &format!("refs/remotes/upstream/{}", branch),
+            base,
+            false,
+            "",
+        )
+        .unwrap();
+    testutil::commit(&git.repo, "a", "b");
+    assert_eq!(
+        vec![("upstream".to_string(), (1, 0))],
+        git.divergences("upstream").unwrap()
+    );
+}
+
+/// `9999+` when the count was capped.
+fn format_commit_count(count: usize) -> String {
+    if count > MAX_COMMIT_COUNT {
+        format!("{}+", MAX_COMMIT_COUNT)
+    } else {
+        count.to_string()
+    }
+}
+
+#[test]
+fn test_format_commit_count() {
+    assert_eq!("1", format_commit_count(1));
+    assert_eq!("9999", format_commit_count(9999));
+    assert_eq!("9999+", format_commit_count(10000));
+}
+
+/// Render the incoming commits like `⇣3 incoming (fetched 2h ago)`, the time of the last fetch
+/// makes clear that the count isn't live.
+fn render_incoming(
+    incoming: usize,
+    last_fetch: Option<Duration>,
+    unicode: bool,
+    theme: &Theme,
+    shell: &Shell,
+) -> String {
+    let mut s = format!(
+        "{}{}{} incoming",
+        theme.incoming.to_str(shell),
+        if unicode { "⇣" } else { "v" },
+        incoming
+    );
+    if let Some(last_fetch) = last_fetch {
+        let _ = write!(
+            &mut s,
+            " (fetched {})",
+            humanize_relative(&last_fetch, "ago")
+        );
+    }
+    s.push(' ');
+    s
+}
+
+#[test]
+fn test_render_incoming() {
+    let render = |last_fetch, unicode| {
+        strip_escapes(&render_incoming(
+            3,
+            last_fetch,
+            unicode,
+            &Theme::DEFAULT,
+            &Shell::Bash,
+        ))
+    };
+    assert_eq!("⇣3 incoming ", render(None, true));
+    assert_eq!(
+        "v3 incoming (fetched 2h ago) ",
+        render(Some(Duration::from_secs(60 * 60 * 2)), false)
+    );
+}
+
+/// Commits ahead and behind of a remote, with the remote name.
+type Divergence = (String, (usize, usize));
+
+/// Render divergences like `↑2(origin) ↓5(upstream)`, leaving out zero counts. `None` if
+/// there's nothing to show.
+fn render_divergences(
+    divergences: &[Divergence],
+    unicode: bool,
+    theme: &Theme,
+    shell: &Shell,
+) -> Option<String> {
+    let (up, down) = if unicode { ("↑", "↓") } else { ("^", "v") };
+    let mut parts = Vec::new();
+    for (remote, (ahead, behind)) in divergences {
+        if *ahead > 0 {
+            parts.push(format!("{}{}({})", up, ahead, remote));
+        }
+        if *behind > 0 {
+            parts.push(format!("{}{}({})", down, behind, remote));
+        }
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "{}{} ",
+            theme.warning.to_str(shell),
+            parts.join(" ")
+        ))
+    }
+}
+
+#[test]
+fn test_render_divergences() {
+    let theme = Theme::DEFAULT;
+    assert_eq!(None, render_divergences(&[], true, &theme, &Shell::Bash));
+    assert_eq!(
+        None,
+        render_divergences(&[("origin".into(), (0, 0))], true, &theme, &Shell::Bash)
+    );
+    assert_eq!(
+        Some("\u{001b}[33m↑2(origin) ↓5(upstream) ".into()),
+        render_divergences(
+            &[("origin".into(), (2, 0)), ("upstream".into(), (0, 5))],
+            true,
+            &theme,
+            &Shell::Bash
+        )
+    );
+    assert_eq!(
+        Some("\u{001b}[33m^1(origin) v1(origin) ".into()),
+        render_divergences(&[("origin".into(), (1, 1))], false, &theme, &Shell::Bash)
+    );
+}
+
+#[derive(Default)]
+struct StatusSummary {
+    dirty: usize,
+    conflicts: usize,
+    ahead: usize,
+}
+
+impl StatusSummary {
+    /// Render the enabled `parts`, e.g. `[*2↑1✖0]`, colored by the most severe one. `None` if
+    /// they are all zero.
+    fn render(
+        &self,
+        parts: &[StatusPart],
+        unicode: bool,
+        theme: &Theme,
+        shell: &Shell,
+    ) -> Option<String> {
+        let count = |part: &StatusPart| match part {
+            StatusPart::Dirty => self.dirty,
+            StatusPart::Ahead => self.ahead,
+            StatusPart::Conflicts => self.conflicts,
+        };
+        if parts.iter().all(|x| count(x) == 0) {
+            return None;
+        }
+        let color = if parts.contains(&StatusPart::Conflicts) && self.conflicts > 0 {
+            theme.error
+        } else {
+            theme.warning
+        };
+        let mut s = format!("{}[", color.to_str(shell));
+        for part in parts {
+            let _ = write!(&mut s, "{}{}", part.symbol(unicode), count(part));
+        }
+        s.push_str("] ");
+        Some(s)
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct IndexStatus {
+    staged: usize,
+    unstaged: usize,
+}
+
+impl IndexStatus {
+    /// Render e.g. `●2 +3`, staged changes in the success color and unstaged ones as a warning.
+    /// Zero counts are left out, `None` if both are zero.
+    fn render(&self, unicode: bool, theme: &Theme, shell: &Shell) -> Option<String> {
+        if self.staged == 0 && self.unstaged == 0 {
+            return None;
+        }
+        let mut s = String::new();
+        if self.staged > 0 {
+            let _ = write!(
+                &mut s,
+                "{}{}{} ",
+                theme.success.to_str(shell),
+                if unicode { "●" } else { "S" },
+                self.staged
+            );
+        }
+        if self.unstaged > 0 {
+            let _ = write!(&mut s, "{}+{} ", theme.warning.to_str(shell), self.unstaged);
+        }
+        Some(s)
+    }
+}
+
+#[test]
+fn test_index_status_render() {
+    let render = |staged, unstaged| {
+        IndexStatus { staged, unstaged }
+            .render(true, &Theme::DEFAULT, &Shell::Bash)
+            .map(|x| strip_escapes(&x))
+    };
+    assert_eq!(None, render(0, 0));
+    assert_eq!(Some("●2 +3 ".into()), render(2, 3));
+    assert_eq!(Some("+1 ".into()), render(0, 1));
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum StatusPart {
+    Dirty,
+    Ahead,
+    Conflicts,
+}
+
+impl StatusPart {
+    const SUPPORTED: [&'static str; 3] = ["dirty", "ahead", "conflicts"];
+
+    const fn symbol(&self, unicode: bool) -> &str {
+        match self {
+            Self::Dirty => "*",
+            Self::Ahead => {
+                if unicode {
+                    "↑"
+                } else {
+                    "^"
+                }
+            }
+            Self::Conflicts => {
+                if unicode {
+                    "✖"
+                } else {
+                    "x"
+                }
+            }
+        }
+    }
+}
+
+impl TryFrom<&str> for StatusPart {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "dirty" => Ok(Self::Dirty),
+            "ahead" => Ok(Self::Ahead),
+            "conflicts" => Ok(Self::Conflicts),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parts of the status summary to show, `None` if it's disabled. When `--status-summary` is
+/// given without values every part is shown.
+fn status_parts(matches: &ArgMatches) -> Option<Vec<StatusPart>> {
+    if !matches.is_present("status_summary") {
+        return None;
+    }
+    let parts = matches
+        .values_of("status_summary")
+        .into_iter()
+        .flatten()
+        .filter_map(|x| StatusPart::try_from(x).ok())
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        Some(vec![
+            StatusPart::Dirty,
+            StatusPart::Ahead,
+            StatusPart::Conflicts,
+        ])
+    } else {
+        Some(parts)
+    }
+}
+
+#[test]
+fn test_status_summary_render() {
+    let all = [StatusPart::Dirty, StatusPart::Ahead, StatusPart::Conflicts];
+    let summary = StatusSummary {
+        dirty: 2,
+        ahead: 1,
+        ..Default::default()
+    };
+    assert_eq!(
+        Some("\u{001b}[33m[*2↑1✖0] ".to_owned()),
+        summary.render(&all, true, &Theme::DEFAULT, &Shell::Bash)
+    );
+    assert_eq!(
+        Some("\u{001b}[33m[^1] ".to_owned()),
+        summary.render(&[StatusPart::Ahead], false, &Theme::DEFAULT, &Shell::Bash)
+    );
+    assert_eq!(
+        None,
+        summary.render(
+            &[StatusPart::Conflicts],
+            true,
+            &Theme::DEFAULT,
+            &Shell::Bash
+        )
+    );
+    let summary = StatusSummary {
+        conflicts: 1,
+        ..Default::default()
+    };
+    assert_eq!(
+        Some("\u{001b}[31m[*0↑0✖1] ".to_owned()),
+        summary.render(&all, true, &Theme::DEFAULT, &Shell::Bash)
+    );
+}
+
+/// The email of the identity in `repo` (the name, without one) if the name or the email differ
+/// from the ones in `global`.
+fn differing_identity(repo: &git2::Config, global: &git2::Config) -> Option<String> {
+    let identity = |config: &git2::Config| {
+        (
+            config.get_string("user.name").ok(),
+            config.get_string("user.email").ok(),
+        )
+    };
+    let (name, email) = identity(repo);
+    if (name.clone(), email.clone()) == identity(global) {
+        return None;
+    }
+    email.or(name)
+}
+
+#[test]
+fn test_differing_identity() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut global = git2::Config::open(&dir.path().join("global")).unwrap();
+    let mut repo = git2::Config::open(&dir.path().join("repo")).unwrap();
+    assert_eq!(None, differing_identity(&repo, &global));
+    global.set_str("user.name", "Me").unwrap();
+    global.set_str("user.email", "me@home.example").unwrap();
+    repo.set_str("user.name", "Me").unwrap();
+    repo.set_str("user.email", "me@home.example").unwrap();
+    assert_eq!(None, differing_identity(&repo, &global));
+    repo.set_str("user.email", "me@work.example").unwrap();
+    assert_eq!(
+        Some("me@work.example".into()),
+        differing_identity(&repo, &global)
+    );
+    repo.remove("user.email").unwrap();
+    assert_eq!(Some("Me".into()), differing_identity(&repo, &global));
+}
+
+/// Per-repo settings, when set they take precedence over the corresponding CLI flags.
+#[derive(Default)]
+struct RepoConfig {
+    /// Don't show any git information for this repo.
+    hidden: Option<bool>,
+    short_path: Option<bool>,
+    unicode: Option<bool>,
+    /// `false` skips every check of the working tree, the slow part in a huge repo.
+    show_dirty: Option<bool>,
+}
+
+/// Label of the nix shell, given the values of `$IN_NIX_SHELL` and `$name` (set by
+/// `nix-shell`/`nix develop`). `None` when not in a nix shell.
+fn nix_shell_label(in_nix_shell: Option<&str>, name: Option<&str>) -> Option<String> {
+    let kind = in_nix_shell.filter(|x| !x.is_empty())?;
+    let label = name.filter(|x| !x.is_empty()).unwrap_or(kind);
+    Some(if kind == "pure" && label != kind {
+        format!("{} (pure)", label)
+    } else {
+        label.into()
+    })
+}
+
+#[test]
+fn test_nix_shell_label() {
+    assert_eq!(None, nix_shell_label(None, Some("foo")));
+    assert_eq!(Some("impure".into()), nix_shell_label(Some("impure"), None));
+    assert_eq!(Some("pure".into()), nix_shell_label(Some("pure"), Some("")));
+    assert_eq!(
+        Some("foo".into()),
+        nix_shell_label(Some("impure"), Some("foo"))
+    );
+    assert_eq!(
+        Some("foo (pure)".into()),
+        nix_shell_label(Some("pure"), Some("foo"))
+    );
+}
+
+/// How many flags `--env-flags` lists, the others are only counted.
+const MAX_ENV_FLAGS: usize = 3;
+
+/// Names, without `prefix` and lowercase, of the variables in `vars` starting with `prefix`
+/// and set to a true value, i.e. anything but empty, `0`, `false`, `no` and `off`. Sorted.
+fn env_flags(vars: impl Iterator<Item = (String, String)>, prefix: &str) -> Vec<String> {
+    let mut flags = vars
+        .filter(|(_, value)| {
+            let value = value.trim().to_ascii_lowercase();
+            !["", "0", "false", "no", "off"].contains(&value.as_str())
+        })
+        .filter_map(|(name, _)| Some(name.strip_prefix(prefix)?.to_ascii_lowercase()))
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    flags.sort();
+    flags
+}
+
+/// `flags` separated by commas, the ones after `MAX_ENV_FLAGS` only counted, e.g. `a,b,c +2`.
+fn render_env_flags(flags: &[String]) -> String {
+    let shown = flags[..flags.len().min(MAX_ENV_FLAGS)].join(",");
+    match flags.len().saturating_sub(MAX_ENV_FLAGS) {
+        0 => shown,
+        rest => format!("{} +{}", shown, rest),
+    }
+}
+
+#[test]
+fn test_env_flags() {
+    let vars = [
+        ("FEATURE_NEW_UI", "1"),
+        ("FEATURE_BETA", "true"),
+        ("FEATURE_OLD", "0"),
+        ("FEATURE_LEGACY", "False"),
+        ("FEATURE_EMPTY", ""),
+        ("FEATURE_", "1"),
+        ("OTHER_FEATURE_X", "1"),
+    ]
+    .iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()));
+    let flags = env_flags(vars, "FEATURE_");
+    assert_eq!(vec!["beta", "new_ui"], flags);
+    assert_eq!("beta,new_ui", render_env_flags(&flags));
+    let many = ["a", "b", "c", "d", "e"]
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!("a,b,c +2", render_env_flags(&many));
+}
+
+/// Symbols of the branches following the usual naming conventions, see `--branch-symbols`.
+const DEFAULT_BRANCH_SYMBOLS: [(&str, &str); 5] = [
+    ("feature/", "✨"),
+    ("bugfix/", "🐛"),
+    ("hotfix/", "🔥"),
+    ("release/", "🚀"),
+    ("detached", "➦"),
+];
+
+/// Symbol of the first prefix of `branch` found in `custom`, then in the defaults. The
+/// `detached` key matches a detached HEAD instead of a prefix.
+fn branch_type_symbol<'a>(
+    branch: &str,
+    detached: bool,
+    custom: &[(&str, &'a str)],
+) -> Option<&'a str> {
+    custom
+        .iter()
+        .chain(DEFAULT_BRANCH_SYMBOLS.iter())
+        .find(|(prefix, _)| {
+            if *prefix == "detached" {
+                detached
+            } else {
+                !detached && branch.starts_with(prefix)
+            }
+        })
+        .map(|(_, symbol)| *symbol)
+}
+
+#[test]
+fn test_branch_type_symbol() {
+    assert_eq!(None, branch_type_symbol("main", false, &[]));
+    assert_eq!(Some("✨"), branch_type_symbol("feature/x", false, &[]));
+    assert_eq!(Some("➦"), branch_type_symbol("HEAD", true, &[]));
+    let custom = [("feature/", "F"), ("wip/", "W")];
+    assert_eq!(Some("F"), branch_type_symbol("feature/x", false, &custom));
+    assert_eq!(Some("W"), branch_type_symbol("wip/x", false, &custom));
+    assert_eq!(Some("🔥"), branch_type_symbol("hotfix/x", false, &custom));
+}
+
+/// Name of the active conda environment, given the values of `$CONDA_DEFAULT_ENV` and
+/// `$CONDA_PROMPT_MODIFIER` (e.g. `(env) `). `None` when no environment is active, or when it's
+/// `base` and `show_base` is false.
+fn conda_env(default_env: Option<&str>, modifier: Option<&str>, show_base: bool) -> Option<String> {
+    let env = default_env.filter(|x| !x.is_empty()).or_else(|| {
+        let modifier = modifier?.trim();
+        modifier
+            .strip_prefix('(')
+            .and_then(|x| x.strip_suffix(')'))
+            .filter(|x| !x.is_empty())
+    })?;
+    if env == "base" && !show_base {
+        None
+    } else {
+        Some(env.into())
+    }
+}
+
+#[test]
+fn test_conda_env() {
+    assert_eq!(None, conda_env(None, None, false));
+    assert_eq!(Some("ml".into()), conda_env(Some("ml"), None, false));
+    assert_eq!(Some("ml".into()), conda_env(Some(""), Some("(ml) "), false));
+    assert_eq!(None, conda_env(Some("base"), Some("(base) "), false));
+    assert_eq!(Some("base".into()), conda_env(Some("base"), None, true));
+}
+
+/// Compute a segment that may fail, e.g. because of an old libgit2, omitting it on errors
+/// instead of failing the whole prompt. Errors are printed to stderr when `SPROMPT_DEBUG` is
+/// set.
+fn try_segment<T, E: fmt::Display>(name: &str, f: impl FnOnce() -> Result<T, E>) -> Option<T> {
+    match f() {
+        Ok(x) => Some(x),
+        Err(e) => {
+            if env::var_os("SPROMPT_DEBUG").is_some_and(|x| !x.is_empty()) {
+                eprintln!("sprompt: {} segment: {}", name, e);
+            }
+            None
+        }
+    }
+}
+
+#[test]
+fn test_try_segment() {
+    assert_eq!(Some(1), try_segment("a", || Ok::<_, String>(1)));
+    assert_eq!(None, try_segment("a", || Err::<i32, _>("failed")));
+}
+
+/// State of the `.envrc` of the cwd.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direnv {
+    Loaded,
+    /// Not loaded, usually because it still needs a `direnv allow`.
+    Blocked,
+}
+
+/// Whether direnv loaded the `.envrc` of `cwd`, the closest one in it or in a parent like for
+/// direnv, given the value of `$DIRENV_DIR`: the directory of the loaded one prefixed with `-`.
+/// `None` without an `.envrc`.
+fn direnv_status(cwd: &Path, direnv_dir: Option<&OsStr>) -> Option<Direnv> {
+    let dir = cwd.ancestors().find(|x| x.join(".envrc").is_file())?;
+    let loaded = direnv_dir
+        .and_then(|x| x.to_str())
+        .and_then(|x| x.strip_prefix('-'))
+        .map(Path::new)
+        .is_some_and(|x| x == dir || x.canonicalize().ok() == dir.canonicalize().ok());
+    Some(if loaded {
+        Direnv::Loaded
+    } else {
+        Direnv::Blocked
+    })
+}
+
+#[test]
+fn test_direnv_status() {
+    let dir = tempfile::tempdir().unwrap();
+    let project = dir.path().join("project");
+    fs::create_dir_all(project.join("src")).unwrap();
+    assert_eq!(None, direnv_status(&project, None));
+    fs::write(project.join(".envrc"), "export A=1").unwrap();
+    let loaded = OsString::from(format!("-{}", project.display()));
+    assert_eq!(Some(Direnv::Blocked), direnv_status(&project, None));
+    assert_eq!(Some(Direnv::Loaded), direnv_status(&project, Some(&loaded)));
+    assert_eq!(
+        Some(Direnv::Loaded),
+        direnv_status(&project.join("src"), Some(&loaded))
+    );
+    // Loaded without the dash, or for another directory.
+    let unprefixed = project.clone().into_os_string();
+    assert_eq!(
+        Some(Direnv::Blocked),
+        direnv_status(&project, Some(&unprefixed))
+    );
+    fs::write(project.join("src/.envrc"), "export B=1").unwrap();
+    assert_eq!(
+        Some(Direnv::Blocked),
+        direnv_status(&project.join("src"), Some(&loaded))
+    );
+}
+
+/// An ssh agent reachable through `$SSH_AUTH_SOCK`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SshAgent {
+    /// Running without keys, or they couldn't be listed.
+    Empty,
+    Loaded,
+}
+
+/// How long the keys listed by the ssh agent are trusted before asking again.
+const SSH_AGENT_CACHE: Duration = Duration::from_secs(60);
+
+/// The ssh agent, `None` if there's none. Listing its keys needs `ssh-add -l`, whose exit
+/// status is cached for `SSH_AGENT_CACHE`, for each socket.
+fn ssh_agent(now: SystemTime) -> Option<SshAgent> {
+    let socket = env::var("SSH_AUTH_SOCK").ok().filter(|x| !x.is_empty())?;
+    if !Path::new(&socket).exists() {
+        return None;
+    }
+    let now = now.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let state = State::global("ssh-agent");
+    let cached = state
+        .as_ref()
+        .and_then(|x| x.read())
+        .and_then(|x| cached_ssh_add_status(&x, &socket, now));
+    let status = match cached {
+        Some(x) => x,
+        None => {
+            // -1 when ssh-add isn't installed or was killed.
+            let status = Command::new("ssh-add")
+                .arg("-l")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .ok()
+                .and_then(|x| x.code())
+                .unwrap_or(-1);
+            if let Some(state) = state {
+                let _ = state.write(&format!("{} {} {}", now, status, socket));
+            }
+            status
+        }
+    };
+    // 1 means no keys, 2 that the agent can't be reached.
+    match status {
+        0 => Some(SshAgent::Loaded),
+        2 => None,
+        _ => Some(SshAgent::Empty),
+    }
+}
+
+/// The exit status of `ssh-add -l` in the cache written by `ssh_agent`, if it's for `socket`
+/// and still fresh at `now` (in seconds since the epoch).
+fn cached_ssh_add_status(contents: &str, socket: &str, now: u64) -> Option<i32> {
+    let mut parts = contents.trim_end_matches('\n').splitn(3, ' ');
+    let time = parts.next()?.parse::<u64>().ok()?;
+    let status = parts.next()?.parse().ok()?;
+    if parts.next()? == socket && now.saturating_sub(time) < SSH_AGENT_CACHE.as_secs() {
+        Some(status)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_cached_ssh_add_status() {
+    let cache = "1000 0 /tmp/ssh agent/sock";
+    assert_eq!(
+        Some(0),
+        cached_ssh_add_status(cache, "/tmp/ssh agent/sock", 1030)
+    );
+    assert_eq!(
+        None,
+        cached_ssh_add_status(cache, "/tmp/ssh agent/sock", 1060)
+    );
+    assert_eq!(None, cached_ssh_add_status(cache, "/tmp/other", 1030));
+    assert_eq!(None, cached_ssh_add_status("", "/tmp/other", 1030));
+}
+
+/// Whether the command before the last one failed, as recorded by `record_failed`.
+fn previous_failed() -> bool {
+    State::new("last-failed")
+        .and_then(|x| x.read())
+        .is_some_and(|x| x == "1")
+}
+
+/// Record whether the last command failed, see `previous_failed`.
+fn record_failed(failed: bool) {
+    if let Some(state) = State::new("last-failed") {
+        let _ = state.write(if failed { "1" } else { "0" });
+    }
+}
+
+/// The streak of the commands before the last one, see `next_streak`.
+fn stored_streak() -> i64 {
+    State::new("streak")
+        .and_then(|x| x.read())
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(0)
+}
+
+fn record_streak(streak: i64) {
+    if let Some(state) = State::new("streak") {
+        let _ = state.write(&streak.to_string());
+    }
+}
+
+/// The streak of the last commands given the one before the last command: the number of
+/// consecutive successes, or minus the number of consecutive failures.
+fn next_streak(previous: i64, failed: bool) -> i64 {
+    match (failed, previous) {
+        (false, x) if x > 0 => x.saturating_add(1),
+        (false, _) => 1,
+        (true, x) if x < 0 => x.saturating_sub(1),
+        (true, _) => -1,
+    }
+}
+
+/// A happy face after `HAPPY_STREAK` successes, a sad one after a failure, nothing otherwise.
+fn mood_symbol(streak: i64, unicode: bool) -> Option<&'static str> {
+    match (streak, unicode) {
+        (x, true) if x >= HAPPY_STREAK => Some("😊"),
+        (x, false) if x >= HAPPY_STREAK => Some(":)"),
+        (x, true) if x < 0 => Some("😞"),
+        (x, false) if x < 0 => Some(":("),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_mood() {
+    let mut streak = 0;
+    for _ in 0..HAPPY_STREAK - 1 {
+        streak = next_streak(streak, false);
+        assert_eq!(None, mood_symbol(streak, true));
+    }
+    streak = next_streak(streak, false);
+    assert_eq!(Some(":)"), mood_symbol(streak, false));
+    streak = next_streak(streak, true);
+    assert_eq!(-1, streak);
+    assert_eq!(Some("😞"), mood_symbol(streak, true));
+    assert_eq!(-2, next_streak(streak, true));
+    assert_eq!(1, next_streak(streak, false));
+}
+
+/// Name of the signal that killed the last command. Shells report it as an exit code of 128
+/// plus the signal number.
+#[cfg(unix)]
+fn signal_name(exit_code: i32) -> Option<&'static str> {
+    const SIGNALS: [(libc::c_int, &str); 20] = [
+        (libc::SIGHUP, "SIGHUP"),
+        (libc::SIGINT, "SIGINT"),
+        (libc::SIGQUIT, "SIGQUIT"),
+        (libc::SIGILL, "SIGILL"),
+        (libc::SIGTRAP, "SIGTRAP"),
+        (libc::SIGABRT, "SIGABRT"),
+        (libc::SIGBUS, "SIGBUS"),
+        (libc::SIGFPE, "SIGFPE"),
+        (libc::SIGKILL, "SIGKILL"),
+        (libc::SIGUSR1, "SIGUSR1"),
+        (libc::SIGSEGV, "SIGSEGV"),
+        (libc::SIGUSR2, "SIGUSR2"),
+        (libc::SIGPIPE, "SIGPIPE"),
+        (libc::SIGALRM, "SIGALRM"),
+        (libc::SIGTERM, "SIGTERM"),
+        (libc::SIGCHLD, "SIGCHLD"),
+        (libc::SIGCONT, "SIGCONT"),
+        (libc::SIGSTOP, "SIGSTOP"),
+        (libc::SIGTSTP, "SIGTSTP"),
+        (libc::SIGXCPU, "SIGXCPU"),
+    ];
+    let signal = exit_code.checked_sub(128).filter(|x| *x > 0)?;
+    SIGNALS
+        .iter()
+        .find(|(number, _)| *number == signal)
+        .map(|(_, name)| *name)
+}
+
+#[cfg(not(unix))]
+fn signal_name(_exit_code: i32) -> Option<&'static str> {
+    None
+}
+
+#[cfg(unix)]
+#[test]
+fn test_signal_name() {
+    assert_eq!(Some("SIGINT"), signal_name(130));
+    assert_eq!(Some("SIGKILL"), signal_name(137));
+    assert_eq!(None, signal_name(0));
+    assert_eq!(None, signal_name(1));
+    assert_eq!(None, signal_name(128));
+}
+
+/// 1-minute load average, `None` on platforms where it isn't available.
+#[cfg(unix)]
+fn load_average() -> Option<f64> {
+    let mut loads = [0.0];
+    if unsafe { libc::getloadavg(loads.as_mut_ptr(), 1) } == 1 {
+        Some(loads[0])
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn load_average() -> Option<f64> {
+    None
+}
+
+/// Whether `dir` contains hooks that git would run, i.e. executable files other than the
+/// `.sample` ones created with every repo.
+fn has_hooks_in(dir: &Path) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    entries.filter_map(|x| x.ok()).any(|x| {
+        !x.file_name().to_string_lossy().ends_with(".sample")
+            // Follows symlinks, hook managers often install them.
+            && fs::metadata(x.path()).is_ok_and(|x| x.is_file() && is_executable(&x))
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    true
+}
+
+#[cfg(unix)]
+#[cfg(test)]
+fn set_executable(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_has_hooks_in() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(!has_hooks_in(&dir.path().join("missing")));
+    fs::write(dir.path().join("pre-push.sample"), "#!/bin/sh").unwrap();
+    set_executable(&dir.path().join("pre-push.sample"));
+    assert!(!has_hooks_in(dir.path()));
+    fs::write(dir.path().join("pre-push"), "#!/bin/sh").unwrap();
+    assert!(!has_hooks_in(dir.path()));
+    set_executable(&dir.path().join("pre-push"));
+    assert!(has_hooks_in(dir.path()));
+    fs::remove_file(dir.path().join("pre-push")).unwrap();
+    fs::create_dir(dir.path().join("pre-push")).unwrap();
+    assert!(!has_hooks_in(dir.path()));
+}
+
+/// Percentage of the filesystem containing `path` available to unprivileged users, `None` if
+/// it can't be inspected or on platforms without `statvfs`.
+#[cfg(unix)]
+fn free_space_percent(path: &Path) -> Option<f64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    if stat.f_blocks == 0 {
+        // Pseudo filesystems like /proc.
+        return None;
+    }
+    Some(stat.f_bavail as f64 / stat.f_blocks as f64 * 100.0)
+}
+
+#[cfg(not(unix))]
+fn free_space_percent(_path: &Path) -> Option<f64> {
+    None
+}
+
+#[test]
+fn test_free_space_percent() {
+    let free = free_space_percent(Path::new("/")).unwrap();
+    assert!((0.0..=100.0).contains(&free));
+    assert_eq!(None, free_space_percent(Path::new("/proc")));
+    assert_eq!(None, free_space_percent(Path::new("/nonexistent")));
+}
+
+/// Types of the network filesystems, as listed in `/proc/mounts`.
+const NETWORK_FS_TYPES: [&str; 10] = [
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "fuse.sshfs",
+    "afs",
+    "ceph",
+    "glusterfs",
+    "fuse.rclone",
+];
+
+/// Whether `path` is on a network filesystem, where even looking for a git repo can take
+/// seconds. Only known on Linux.
+#[cfg(target_os = "linux")]
+fn is_network_fs(path: &Path) -> bool {
+    fs::read_to_string("/proc/mounts")
+        .ok()
+        .and_then(|mounts| mount_fs_type(&mounts, path).map(|x| NETWORK_FS_TYPES.contains(&x)))
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_network_fs(_path: &Path) -> bool {
+    false
+}
+
+/// Type of the filesystem containing `path`, i.e. of the deepest mount point above it, given
+/// the contents of `/proc/mounts`.
+fn mount_fs_type<'a>(mounts: &'a str, path: &Path) -> Option<&'a str> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let (point, fs_type) = (fields.nth(1)?, fields.next()?);
+            // Spaces, tabs, newlines and backslashes are escaped as octal, e.g. `\040`.
+            let point = point
+                .replace("\\040", " ")
+                .replace("\\011", "\t")
+                .replace("\\012", "\n")
+                .replace("\\134", "\\");
+            Some((PathBuf::from(point), fs_type))
+        })
+        .filter(|(point, _)| path.starts_with(point))
+        // Among mounts on the same point the last one wins, and so does `max_by_key`.
+        .max_by_key(|(point, _)| point.components().count())
+        .map(|(_, fs_type)| fs_type)
+}
+
+#[test]
+fn test_mount_fs_type() {
+    let mounts = "\
+/dev/sda1 / ext4 rw,relatime 0 0
+server:/export /mnt/nfs nfs4 rw,relatime 0 0
+//nas/share /mnt/my\\040share cifs rw 0 0
+tmpfs /mnt/nfs/tmp tmpfs rw 0 0
+me@host:/ /mnt/ssh fuse.sshfs rw 0 0
+";
+    let fs_type = |path| mount_fs_type(mounts, Path::new(path));
+    assert_eq!(Some("ext4"), fs_type("/home/me"));
+    assert_eq!(Some("nfs4"), fs_type("/mnt/nfs/a/b"));
+    assert_eq!(Some("tmpfs"), fs_type("/mnt/nfs/tmp/a"));
+    assert_eq!(Some("cifs"), fs_type("/mnt/my share/a"));
+    assert_eq!(Some("fuse.sshfs"), fs_type("/mnt/ssh"));
+    assert_eq!(Some("ext4"), fs_type("/mnt/nfsx"));
+    assert_eq!(None, mount_fs_type("", Path::new("/")));
+}
+
+/// Open file descriptors and their soft limit. The descriptors are sprompt's own, i.e. the ones
+/// inherited from the shell, which every command starts with. `None` without `/proc` or when
+/// there's no limit.
+#[cfg(target_os = "linux")]
+fn open_files() -> Option<(u64, u64)> {
+    let mut limit = std::mem::MaybeUninit::<libc::rlimit>::uninit();
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let limit = unsafe { limit.assume_init() }.rlim_cur;
+    if limit == libc::RLIM_INFINITY {
+        return None;
+    }
+    // Minus the one reading the directory.
+    let open = fs::read_dir("/proc/self/fd")
+        .ok()?
+        .count()
+        .saturating_sub(1);
+    Some((open as u64, limit))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_files() -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_open_files() {
+    let (open, limit) = open_files().unwrap();
+    // At least stdin, stdout and stderr.
+    assert!(open >= 3);
+    assert!(open < limit);
+}
+
+fn is_near_limit(open: u64, limit: u64) -> bool {
+    open.saturating_mul(100) >= limit.saturating_mul(HIGH_OPEN_FILES_PERCENT)
+}
+
+#[test]
+fn test_is_near_limit() {
+    assert!(!is_near_limit(10, 1024));
+    assert!(!is_near_limit(818, 1024));
+    assert!(is_near_limit(820, 1024));
+    assert!(is_near_limit(1024, 1024));
+    assert!(is_near_limit(0, 0));
+}
+
+fn is_high_load(load: f64, cpus: usize) -> bool {
+    load > cpus as f64 * HIGH_LOAD_PER_CPU
+}
+
+#[test]
+fn test_is_high_load() {
+    assert!(!is_high_load(0.5, 1));
+    assert!(is_high_load(1.5, 1));
+    assert!(!is_high_load(7.9, 8));
+    assert!(is_high_load(8.1, 8));
+}
+
+/// Number of pending OS updates, as written by a periodic job to `$SPROMPT_UPDATES_FILE`
+/// (`$XDG_STATE_HOME/sprompt/updates` by default). `None` if the file is missing or the count
+/// is zero.
+fn pending_updates() -> Option<u64> {
+    let contents = match env::var_os("SPROMPT_UPDATES_FILE").filter(|x| !x.is_empty()) {
+        Some(path) => fs::read_to_string(path).ok()?,
+        None => State::global("updates")?.read()?,
+    };
+    parse_updates(&contents)
+}
+
+fn parse_updates(contents: &str) -> Option<u64> {
+    contents.trim().parse().ok().filter(|x| *x > 0)
+}
+
+#[test]
+fn test_parse_updates() {
+    assert_eq!(Some(12), parse_updates("12\n"));
+    assert_eq!(None, parse_updates("0"));
+    assert_eq!(None, parse_updates(""));
+    assert_eq!(None, parse_updates("many"));
+}
+
+/// Color of the path: red in read-only directories, yellow when the short path is shown
+/// outside of a git repo, where it can't start from the repo, cyan otherwise (with the default
+/// theme).
+fn path_color(theme: &Theme, readonly: bool, short_path_outside_git: bool) -> ThemeColor {
+    if readonly {
+        theme.path_readonly
+    } else if short_path_outside_git {
+        theme.path_outside_repo
+    } else {
+        theme.path
+    }
+}
+
+#[test]
+fn test_path_color() {
+    let theme = Theme::DEFAULT;
+    assert_eq!(Color::Cyan, path_color(&theme, false, false).color);
+    assert_eq!(Color::Red, path_color(&theme, true, false).color);
+    assert_eq!(Color::Red, path_color(&theme, true, true).color);
+    assert_eq!(Color::Yellow, path_color(&theme, false, true).color);
+}
+
+/// Whether the current user can't write to the directory `path`, `None` if it can't be
+/// inspected.
+fn is_readonly(path: &Path) -> Option<bool> {
+    let metadata = fs::metadata(path).ok()?;
+    if metadata.permissions().readonly() {
+        return Some(true);
+    }
+    // The permission bits alone don't say whether they apply to the current user.
+    #[cfg(unix)]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        Some(unsafe { libc::access(path.as_ptr(), libc::W_OK) } != 0)
+    }
+    #[cfg(not(unix))]
+    Some(false)
+}
+
+#[test]
+fn test_is_readonly() {
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(Some(false), is_readonly(dir.path()));
+    assert_eq!(None, is_readonly(&dir.path().join("missing")));
+}
+
+/// Cut `s` so that, including an ellipsis at the end, it's at most `max_width` columns wide.
+/// `s` is returned as is if it's narrow enough already.
+fn truncate_to_width(s: &str, max_width: usize) -> String {
+    if display_width(s) <= max_width {
+        return s.into();
+    }
+    let mut truncated = String::new();
+    let mut width = 0;
+    for c in s.chars() {
+        let w = c.width().unwrap_or(0);
+        if width + w + 1 > max_width {
+            break;
+        }
+        truncated.push(c);
+        width += w;
+    }
+    truncated.push('…');
+    truncated
+}
+
+/// Pad `s` with spaces on the right so that it's at least `min_width` columns wide.
+fn pad_to_width(s: &str, min_width: usize) -> String {
+    let padding = min_width.saturating_sub(display_width(s));
+    format!("{}{}", s, " ".repeat(padding))
+}
+
+#[test]
+fn test_truncate_and_pad_to_width() {
+    assert_eq!("main", truncate_to_width("main", 4));
+    assert_eq!("fea…", truncate_to_width("feature/x", 4));
+    assert_eq!("main  ", pad_to_width("main", 6));
+    assert_eq!("main", pad_to_width("main", 2));
+    assert_eq!("日本 ", pad_to_width("日本", 5));
+}
+
+/// With `--adaptive-path` the path can take up to this fraction (as in `1 / n`) of the terminal
+/// width.
+const ADAPTIVE_PATH_FRACTION: usize = 3;
+
+/// Shorten `path` until it fits in `max_width` columns: first drop leading components, then
+/// truncate the last one with an ellipsis.
+fn fit_path(path: &str, max_width: usize) -> String {
+    let mut path = path;
+    while display_width(path) > max_width {
+        match path.find('/') {
+            Some(idx) if idx + 1 < path.len() => path = &path[idx + 1..],
+            _ => break,
+        }
+    }
+    truncate_to_width(path, max_width)
+}
+
+#[test]
+fn test_fit_path() {
+    assert_eq!("~/a/b/c", fit_path("~/a/b/c", 10));
+    assert_eq!("~/a/b/c", fit_path("~/a/b/c", 7));
+    assert_eq!("a/b/c", fit_path("~/a/b/c", 6));
+    assert_eq!("c", fit_path("~/a/b/c", 1));
+    assert_eq!("/usr/lib", fit_path("/usr/lib", 8));
+    assert_eq!("usr/lib", fit_path("/usr/lib", 7));
+    assert_eq!("proj…", fit_path("~/projects", 5));
+    assert_eq!("…", fit_path("~/projects", 1));
+}
+
+/// Shorten `path` until it fits in `max_width` columns by replacing components in the middle
+/// with an ellipsis, keeping at least the first and the last one, e.g. `~/a/…/y/z`. Slightly
+/// more components are kept at the end, where the cwd is. Falls back to `fit_path` when even
+/// that is too wide.
+fn fit_path_middle(path: &str, max_width: usize) -> String {
+    if display_width(path) <= max_width {
+        return path.into();
+    }
+    let parts = path.split('/').collect::<Vec<_>>();
+    // An absolute path starts with an empty component, the first one is the one after it.
+    let skip = usize::from(parts[0].is_empty());
+    for kept in (2..parts.len() - skip).rev() {
+        // With an odd number, the extra component goes to the end.
+        let tail = kept - kept / 2;
+        let head = skip + kept / 2;
+        let shortened = [&parts[..head], &["…"], &parts[parts.len() - tail..]]
+            .concat()
+            .join("/");
+        if display_width(&shortened) <= max_width {
+            return shortened;
+        }
+    }
+    fit_path(path, max_width)
+}
+
+#[test]
+fn test_fit_path_middle() {
+    let path = "~/very/long/path/to/deep/target";
+    assert_eq!(path, fit_path_middle(path, 31));
+    assert_eq!("~/very/long/…/to/deep/target", fit_path_middle(path, 30));
+    assert_eq!("~/very/…/to/deep/target", fit_path_middle(path, 25));
+    assert_eq!("~/very/…/deep/target", fit_path_middle(path, 20));
+    assert_eq!("~/…/deep/target", fit_path_middle(path, 18));
+    assert_eq!("~/…/target", fit_path_middle(path, 10));
+    // Too narrow even for the first and the last component.
+    assert_eq!("target", fit_path_middle(path, 9));
+    assert_eq!("/usr/…/lib", fit_path_middle("/usr/local/share/lib", 10));
+    assert_eq!(
+        "/usr/…/share/lib",
+        fit_path_middle("/usr/local/share/lib", 16)
+    );
+    assert_eq!("share/lib", fit_path_middle("/usr/local/share/lib", 9));
+    // Nothing in the middle to drop.
+    assert_eq!("abc", fit_path_middle("~/abc", 3));
+    assert_eq!("/usr/lib", fit_path_middle("/usr/lib", 8));
+    assert_eq!("日本/…/c", fit_path_middle("日本/a/b/c", 8));
+}
+
+/// Render the last `repo_depth` components of `path` with `style` and the ones before them,
+/// outside of the repo, with `dim_style`.
+fn dim_outside_repo(path: &str, repo_depth: usize, dim_style: &str, style: &str) -> String {
+    let parts = path.split('/').collect::<Vec<_>>();
+    let boundary = parts.len().saturating_sub(repo_depth);
+    if boundary == 0 {
+        return format!("{}{}", style, path);
+    }
+    format!(
+        "{}{}/{}{}",
+        dim_style,
+        parts[..boundary].join("/"),
+        style,
+        parts[boundary..].join("/")
+    )
+}
+
+#[test]
+fn test_dim_outside_repo() {
+    assert_eq!(
+        "<~/projects/>sprompt/src",
+        dim_outside_repo("~/projects/sprompt/src", 2, "<", ">")
+    );
+    assert_eq!("</>repo", dim_outside_repo("/repo", 1, "<", ">"));
+    assert_eq!(">sprompt/src", dim_outside_repo("sprompt/src", 2, "<", ">"));
+    assert_eq!(">src", dim_outside_repo("src", 2, "<", ">"));
+}
+
+fn write_init_code(path: &Path, code: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, format!("{}\n", code))
+}
+
+#[test]
+fn test_write_init_code() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a/b/sprompt.zsh");
+    write_init_code(&path, "code").unwrap();
+    assert_eq!("code\n", fs::read_to_string(&path).unwrap());
+    assert!(write_init_code(&path.join("x"), "code").is_err());
+}
+
+/// Atomically replace the contents of the prompt cache at `path`, so that the shell never reads
+/// half a prompt. Errors are ignored, like when the cache can't be read the shell renders the
+/// prompt itself.
+fn write_cache(path: &str, prompt: &str) {
+    let tmp = format!("{}.{}.tmp", path, std::process::id());
+    if fs::write(&tmp, prompt).is_err() || fs::rename(&tmp, path).is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+}
+
+/// Quote, like a shell would need, the components of `path` containing whitespace or special
+/// characters. A leading `~` standing for the home directory is left alone.
+fn quote_path(path: &str) -> String {
+    const SPECIAL: &str = "'\"\\$`*?[]{}()<>;&|!#~";
+    path.split('/')
+        .enumerate()
+        .map(|(i, component)| {
+            let needs_quoting = !(i == 0 && component == "~")
+                && component
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control() || SPECIAL.contains(c));
+            if !needs_quoting {
+                component.into()
+            } else if component.chars().any(|c| c.is_control()) {
+                // ANSI-C quoting, so that tabs, newlines, ... are visible.
+                let mut quoted = String::from("$'");
+                for c in component.chars() {
+                    match c {
+                        '\t' => quoted.push_str("\\t"),
+                        '\n' => quoted.push_str("\\n"),
+                        '\'' => quoted.push_str("\\'"),
+                        '\\' => quoted.push_str("\\\\"),
+                        c if c.is_control() => {
+                            let _ = write!(&mut quoted, "\\x{:02x}", c as u32);
+                        }
+                        c => quoted.push(c),
+                    }
+                }
+                quoted.push('\'');
+                quoted
+            } else {
+                format!("'{}'", component.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[test]
+fn test_quote_path() {
+    assert_eq!("~/projects/sprompt", quote_path("~/projects/sprompt"));
+    assert_eq!("/usr/lib", quote_path("/usr/lib"));
+    assert_eq!("~/'my projects'/a", quote_path("~/my projects/a"));
+    assert_eq!("~/$'a\\tb'", quote_path("~/a\tb"));
+    assert_eq!(r"~/'it'\''s'", quote_path("~/it's"));
+    assert_eq!(r#"/'say "hi"'"#, quote_path(r#"/say "hi""#));
+    assert_eq!("/tmp/'~'", quote_path("/tmp/~"));
+}
+
+/// If `short` is None, the full path will be returned.
+/// If `short` is Some, a shorter variant will be returned, in this case we also need to know the
+/// repo name.
+type InsideGitRepo<'a> = Option<&'a str>;
+type Short<'a> = Option<InsideGitRepo<'a>>;
+fn get_current_path(cwd: &Path, short: Short, opts: &PathOptions) -> Option<String> {
+    // UNC paths (`\\server\share`) start with two separators on purpose.
+    let path = if cfg!(windows) {
+        cwd.to_str()?.to_owned()
+    } else {
+        collapse_slashes(cwd.to_str()?)
+    };
+    let home = if cfg!(windows) {
+        env::var_os("USERPROFILE").map(PathBuf::from)
+    } else {
+        None
+    };
+    let path = match home_relative(Path::new(&path), home.as_deref()) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".to_owned(),
+        Some(rest) => format!("~{}{}", MAIN_SEPARATOR, rest.to_str()?),
+        None => path,
+    };
 
-            print!("{}", s);
+    Some(shorten_path(&path, short, opts))
+}
+
+/// The rest of `path` if it's inside `home`. Without `home` any directory in `/home` counts,
+/// e.g. also the home of another user.
+fn home_relative<'a>(path: &'a Path, home: Option<&Path>) -> Option<&'a Path> {
+    if let Some(home) = home {
+        return path.strip_prefix(home).ok();
+    }
+    let mut components = path.components();
+    match (components.next()?, components.next()?, components.next()?) {
+        (Component::RootDir, Component::Normal(dir), Component::Normal(_)) if dir == "home" => {
+            Some(components.as_path())
         }
-        ("init", Some(matches)) => {
-            let mut args = String::from(" ");
-            let unicode = matches.is_present("unicode");
-            if unicode {
-                args.push_str("-u ");
-            }
-            let short_path = matches.is_present("short_path");
-            if short_path {
-                args.push_str("-p ");
-            }
-            let args = args.trim_end();
+        _ => None,
+    }
+}
 
-            let shell = Shell::try_from(matches.value_of("shell").unwrap()).unwrap();
-            println!("{}", shell.init_code(args));
+#[test]
+fn test_home_relative() {
+    let relative = |path, home: Option<&str>| {
+        home_relative(Path::new(path), home.map(Path::new)).and_then(|x| x.to_str())
+    };
+    assert_eq!(Some("src/a"), relative("/home/me/src/a", None));
+    assert_eq!(Some(""), relative("/home/me", None));
+    assert_eq!(None, relative("/home", None));
+    assert_eq!(None, relative("/srv/home/me", None));
+    assert_eq!(Some("src"), relative("/Users/me/src", Some("/Users/me")));
+    assert_eq!(None, relative("/Users/meow", Some("/Users/me")));
+}
+
+#[cfg(windows)]
+#[test]
+fn test_windows_paths() {
+    let opts = PathOptions::default();
+    assert_eq!(
+        Some(Path::new(r"projects\sprompt")),
+        home_relative(
+            Path::new(r"C:\Users\me\projects\sprompt"),
+            Some(Path::new(r"C:\Users\me"))
+        )
+    );
+    let path = r"~\projects\sprompt\src\a";
+    assert_eq!(path, shorten_path(path, None, &opts));
+    assert_eq!(
+        r"sprompt\src\a",
+        shorten_path(path, Some(Some("sprompt")), &opts)
+    );
+    assert_eq!(
+        r"b\c\d",
+        get_current_path(Path::new(r"Z:\a\b\c\d"), Some(None), &opts).unwrap()
+    );
+}
+
+/// Collapse runs of slashes into one and drop the trailing one, e.g. `//a//b/` becomes `/a/b`.
+/// POSIX leaves the meaning of a leading `//` to the system, but Linux and macOS treat it
+/// like `/`, so it's collapsed too.
+fn collapse_slashes(path: &str) -> String {
+    let mut collapsed = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c != '/' || !collapsed.ends_with('/') {
+            collapsed.push(c);
         }
-        _ => unreachable!(),
     }
+    if collapsed.len() > 1 && collapsed.ends_with('/') {
+        collapsed.pop();
+    }
+    collapsed
 }
 
-struct Git {
-    repo: Repository,
+#[test]
+fn test_collapse_slashes() {
+    assert_eq!("/a/b", collapse_slashes("//a//b/"));
+    assert_eq!("/", collapse_slashes("/"));
+    assert_eq!("/", collapse_slashes("///"));
+    assert_eq!("a/b", collapse_slashes("a/b"));
+    let opts = PathOptions::default();
+    let path = |cwd: &str, short| get_current_path(Path::new(cwd), short, &opts).unwrap();
+    assert_eq!("~/src", path("//home//me//src/", None));
+    assert_eq!("~", path("/home//me", None));
+    assert_eq!("b/c/d", path("//a//b/c///d", Some(None)));
 }
 
-impl Git {
-    fn new() -> Option<Self> {
-        let repo = Repository::discover(env::current_dir().ok()?).ok()?;
-        Some(Self { repo })
+struct PathOptions {
+    /// Keep the repo name when the short path would leave it out, see `--always-repo-name`.
+    always_repo_name: bool,
+    /// Number of components of the short path.
+    length: usize,
+}
+
+impl Default for PathOptions {
+    fn default() -> Self {
+        Self {
+            always_repo_name: false,
+            length: 3,
+        }
     }
+}
 
-    // https://github.com/starship/starship/blob/d670212a083e9f1e9c9a2313e7ce5e72e908efa7/src/context.rs
-    fn branch(&self) -> Option<String> {
-        let head = match self.repo.head() {
-            Ok(reference) => reference,
-            Err(e) => {
-                return if e.code() == ErrorCode::UnbornBranch {
-                    // HEAD should only be an unborn branch if the repository is fresh, in that case
-                    // read directly from `.git/HEAD`.
-                    let mut head_path = self.repo.path().to_path_buf();
-                    head_path.push("HEAD");
-                    // Get the first line, then last path segment.
-                    fs::read_to_string(&head_path)
-                        .ok()?
-                        .lines()
-                        .next()?
-                        .trim()
-                        .split('/')
-                        .last()
-                        .map(|r| r.to_owned())
-                } else {
-                    None
-                };
-            }
-        };
-        let shorthand = head.shorthand();
-        shorthand.map(|x| x.into())
+fn shorten_path(path: &str, short: Short, opts: &PathOptions) -> String {
+    const SEP: &str = MAIN_SEPARATOR_STR;
+    let parts = path.split(MAIN_SEPARATOR).collect::<Vec<_>>();
+    let start = parts.len().saturating_sub(opts.length);
+    match short {
+        // Short path inside git tree, starting from the repo if it's among the last components.
+        Some(Some(toplevel)) => match parts.iter().rposition(|x| *x == toplevel) {
+            Some(idx) if idx >= start => parts[idx..].join(SEP),
+            Some(idx) if opts.always_repo_name => match &parts[start + 1..] {
+                [] => format!("{}{}…", parts[idx], SEP),
+                tail => format!("{}{}…{}{}", parts[idx], SEP, SEP, tail.join(SEP)),
+            },
+            _ => parts[start..].join(SEP),
+        },
+        // Short path NOT inside git tree.
+        Some(None) => parts[start..].join(SEP),
+        // Full path.
+        None => path.into(),
     }
+}
 
-    fn toplevel(&self) -> Option<&str> {
-        Some(self.repo.workdir()?.file_name()?.to_str()?)
+#[test]
+fn test_shorten_path() {
+    let opts = PathOptions::default();
+    let always = PathOptions {
+        always_repo_name: true,
+        ..Default::default()
+    };
+    let path = "~/projects/sprompt/src/a/b";
+    assert_eq!(path, shorten_path(path, None, &opts));
+    assert_eq!("src/a/b", shorten_path(path, Some(None), &opts));
+    assert_eq!("src/a/b", shorten_path(path, Some(Some("sprompt")), &opts));
+    assert_eq!(
+        "sprompt/…/a/b",
+        shorten_path(path, Some(Some("sprompt")), &always)
+    );
+    assert_eq!(
+        "sprompt/…/a/b",
+        shorten_path("/sprompt/x/y/z/a/b", Some(Some("sprompt")), &always)
+    );
+    assert_eq!(
+        "sprompt/src/a",
+        shorten_path("~/projects/sprompt/src/a", Some(Some("sprompt")), &always)
+    );
+    assert_eq!(
+        "sprompt/src",
+        shorten_path("~/projects/sprompt/src", Some(Some("sprompt")), &opts)
+    );
+}
+
+#[test]
+fn test_shorten_path_length() {
+    let path = "~/projects/sprompt/src/a/b";
+    let with_length = |length, always_repo_name| PathOptions {
+        always_repo_name,
+        length,
+    };
+    assert_eq!("b", shorten_path(path, Some(None), &with_length(1, false)));
+    assert_eq!(
+        "projects/sprompt/src/a/b",
+        shorten_path(path, Some(None), &with_length(5, false))
+    );
+    assert_eq!(
+        path,
+        shorten_path(path, Some(None), &with_length(10, false))
+    );
+    assert_eq!(
+        "a/b",
+        shorten_path(path, Some(Some("sprompt")), &with_length(2, false))
+    );
+    assert_eq!(
+        "sprompt/src/a/b",
+        shorten_path(path, Some(Some("sprompt")), &with_length(4, false))
+    );
+    assert_eq!(
+        "sprompt/…",
+        shorten_path(path, Some(Some("sprompt")), &with_length(1, true))
+    );
+    assert_eq!(
+        "sprompt/…/b",
+        shorten_path(path, Some(Some("sprompt")), &with_length(2, true))
+    );
+}
+
+#[test]
+fn test_shorten_path_few_components() {
+    let opts = PathOptions::default();
+    for short in &[None, Some(None), Some(Some("other"))] {
+        assert_eq!("~", shorten_path("~", *short, &opts));
+        assert_eq!("/", shorten_path("/", *short, &opts));
+        assert_eq!("/tmp", shorten_path("/tmp", *short, &opts));
+        assert_eq!("~/repo", shorten_path("~/repo", *short, &opts));
     }
+    assert_eq!("repo", shorten_path("~/repo", Some(Some("repo")), &opts));
+    assert_eq!("tmp", shorten_path("/tmp", Some(Some("tmp")), &opts));
+    assert_eq!(
+        "tmp/repo",
+        shorten_path("/tmp/repo", Some(Some("tmp")), &opts)
+    );
 }
 
-/// If `short` is None, the full path will be returned.
-/// If `short` is Some, a shorter variant will be returned, in this case we also need to know the
-/// repo name.
-type InsideGitRepo<'a> = Option<&'a str>;
-type Short<'a> = Option<InsideGitRepo<'a>>;
-fn get_current_path(short: Short) -> Option<String> {
-    let path = env::current_dir().ok()?;
-
-    let path = path.to_str()?.to_owned();
-    let path = if path.starts_with("/home/") {
-        let mut path = path.replace("/home/", "");
-        if let Some(idx) = path.find("/") {
-            path.replace_range(..idx, "");
-            path.insert(0, '~');
-            path
-        } else {
-            "~".to_owned()
+struct InitOptions {
+    /// Keep track of how long commands take, not needed when the elapsed time isn't shown.
+    timing: bool,
+    /// Whether `prompt` ends with a space.
+    trailing_space: bool,
+    /// Prefix of the names of the shell functions and variables, followed by an underscore.
+    function_name: String,
+    /// File caching the prompt, see `sprompt init --cache`.
+    cache: Option<String>,
+    /// Pass the start time of the command in nanoseconds instead of the elapsed seconds.
+    precise_timing: bool,
+    /// bash: set the prompt as `PS1` rather than printing it from `PROMPT_COMMAND`, implied by
+    /// `!trailing_space`.
+    ps1: bool,
+    /// zsh: show the git status in `RPROMPT`, see `--git-on-right`.
+    rprompt: bool,
+    /// Reuse the last prompt unless a command ran, failed or changed the directory, see
+    /// `sprompt init --cache-on-cwd`. Needs `timing`, the timing hooks know whether a command
+    /// ran.
+    cache_on_cwd: bool,
+    /// Arguments of the continuation prompt, see `continuation_args`.
+    continuation_args: String,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        Self {
+            timing: true,
+            trailing_space: true,
+            function_name: "_sprompt".into(),
+            cache: None,
+            precise_timing: false,
+            ps1: false,
+            rprompt: false,
+            cache_on_cwd: false,
+            continuation_args: String::new(),
         }
-    } else {
-        path
-    };
+    }
+}
 
-    match short {
-        // Short path inside git tree.
-        Some(Some(toplevel)) => {
-            let mut parts = path.split('/').rev().take(3).collect::<Vec<_>>();
-            parts.reverse();
-            if parts[1] == toplevel {
-                parts.remove(0);
-            } else if parts[2] == toplevel {
-                parts.remove(0);
-                parts.remove(0);
-            }
-            Some(parts.join("/"))
+/// When to color the prompt, see `--color`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    const SUPPORTED: [&'static str; 3] = ["auto", "always", "never"];
+
+    /// Whether to print escapes, given the values of `$NO_COLOR` and `$TERM`. `auto` follows
+    /// https://no-color.org and leaves them out on dumb terminals.
+    fn enabled(self, no_color: Option<&OsStr>, term: Option<&str>) -> bool {
+        match self {
+            Self::Auto => no_color.filter(|x| !x.is_empty()).is_none() && term != Some("dumb"),
+            Self::Always => true,
+            Self::Never => false,
         }
-        // Short path NOT inside git tree.
-        Some(None) => {
-            let mut parts = path.split('/').rev().take(3).collect::<Vec<_>>();
-            parts.reverse();
-            Some(parts.join("/"))
+    }
+}
+
+impl TryFrom<&str> for ColorMode {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(()),
         }
-        // Full path.
-        None => Some(path),
     }
 }
 
+#[test]
+fn test_color_mode() {
+    for mode in ColorMode::SUPPORTED.iter() {
+        assert!(ColorMode::try_from(*mode).is_ok());
+    }
+    let no_color = Some(OsStr::new("1"));
+    assert!(ColorMode::Auto.enabled(None, Some("xterm-256color")));
+    assert!(ColorMode::Auto.enabled(Some(OsStr::new("")), None));
+    assert!(!ColorMode::Auto.enabled(no_color, Some("xterm-256color")));
+    assert!(!ColorMode::Auto.enabled(None, Some("dumb")));
+    assert!(ColorMode::Always.enabled(no_color, Some("dumb")));
+    assert!(!ColorMode::Never.enabled(None, Some("xterm-256color")));
+}
+
 #[derive(PartialEq)]
 enum Shell {
     Zsh,
@@ -350,59 +6101,555 @@ enum Shell {
 impl Shell {
     const SUPPORTED: [&'static str; 2] = ["zsh", "bash"];
 
-    fn init_code(&self, args: &str) -> String {
+    /// Quote `s` so that it's passed as a single argument to `sprompt prompt` in the init code.
+    fn quote(&self, s: &str) -> String {
+        let quoted = format!("'{}'", s.replace('\'', r"'\''"));
         match self {
-            Self::Zsh => format!(
-                r#"
-preexec() {{
-    _sprompt_preexec_ran=true
-    _sprompt_last_seconds="$SECONDS"
+            // The arguments end up inside a double quoted string.
+            Self::Zsh => {
+                let mut escaped = String::with_capacity(quoted.len());
+                for c in quoted.chars() {
+                    if matches!(c, '\\' | '$' | '"' | '`') {
+                        escaped.push('\\');
+                    }
+                    escaped.push(c);
+                }
+                escaped
+            }
+            Self::Bash => quoted,
+        }
+    }
+
+    /// Reference to the environment variable `name` that is expanded each time the prompt is
+    /// drawn, not when the init code is evaluated.
+    fn var(&self, name: &str) -> String {
+        match self {
+            Self::Zsh => format!(r#""\${}""#, name),
+            Self::Bash => format!(r#""${}""#, name),
+        }
+    }
+
+    fn init_code(&self, args: &str, opts: &InitOptions) -> String {
+        // Prefix of the names of the functions and variables. Put in the code as it's
+        // generated, the quoted `args` are never rewritten.
+        let p = &opts.function_name;
+        // With `--cache`, the prompt is rendered through the wrapper defined at the end.
+        let sprompt = match opts.cache {
+            Some(_) => format!("{}_cached sprompt", p),
+            None => "sprompt".into(),
+        };
+        // Readline only prints a newline when <enter> is pressed on an empty line if it thinks
+        // the prompt isn't empty, but it doesn't know about the one printed in
+        // `PROMPT_COMMAND`, so PS1 needs a visible character. When sprompt prints a trailing
+        // space, step back over it and draw it again, so the prompt looks the same as in other
+        // shells. Without one there's nothing to draw again, the whole prompt goes in PS1.
+        let ps1 = opts.ps1 || !opts.trailing_space;
+        let bash_ps1 = if ps1 {
+            // Expanded when drawing the prompt, the value isn't expanded again, so a `$(...)`
+            // in the cwd is harmless.
+            format!("'${{{}_ps1}}'", p)
+        } else {
+            r"'\[\e[1D\] '".into()
+        };
+        // With `PS1` the prompt goes in a variable, otherwise it's printed right away.
+        let (bash_prompt_start, bash_prompt_end) = if ps1 {
+            (format!(r#"{}_ps1="$("#, p), r#" --ps1-escapes)""#)
+        } else {
+            (String::new(), "")
+        };
+        let timing = opts.timing;
+        let code: String = match self {
+            Self::Zsh => {
+                let (hooks, elapsed_arg) = if timing {
+                    // `EPOCHREALTIME` has nanoseconds, e.g. `1611937462.123456789`.
+                    let (start, elapsed_arg) = if opts.precise_timing {
+                        (
+                            format!(r#"{}_start_ns="${{EPOCHREALTIME/./}}""#, p),
+                            format!(r#" --start-ns "\${}_start_ns""#, p),
+                        )
+                    } else {
+                        (
+                            format!(r#"{}_last_seconds="$SECONDS""#, p),
+                            format!(
+                                r#" --elapsed-seconds "\$(( SECONDS - {}_last_seconds ))""#,
+                                p
+                            ),
+                        )
+                    };
+                    let hooks = format!(
+                        r#"
+{zmodload}{p}_preexec() {{
+    {p}_preexec_ran=true{ran}
+    {start}
 }}
-precmd() {{
-    if [ "$_sprompt_preexec_ran" = false ]; then
-        _sprompt_last_seconds="$SECONDS"
+{p}_precmd() {{
+    if [ "${p}_preexec_ran" = false ]; then
+        {start}
     fi
-    _sprompt_preexec_ran=false
+    {p}_preexec_ran=false
+}}
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec {p}_preexec
+add-zsh-hook precmd {p}_precmd"#,
+                        zmodload = if opts.precise_timing {
+                            "zmodload zsh/datetime\n"
+                        } else {
+                            ""
+                        },
+                        p = p,
+                        start = start,
+                        // `_sprompt_preexec_ran` is reset before `_sprompt_prompt` runs.
+                        ran = if opts.cache_on_cwd {
+                            format!("\n    {}_ran=true", p)
+                        } else {
+                            String::new()
+                        },
+                    );
+                    (hooks, elapsed_arg)
+                } else {
+                    (String::new(), String::new())
+                };
+                // With a right prompt, both are rendered by a single `sprompt prompt` in a
+                // precmd hook, added after the one of the timing so that the elapsed time is up
+                // to date. Every hook starts with `$?` set to the status of the command. The
+                // prompts go in variables, so that a `$(...)` in the cwd isn't expanded. The hook
+                // is also where `--cache-on-cwd` decides whether to render them again.
+                let prompt = if opts.rprompt || opts.cache_on_cwd {
+                    let args = unescape_double_quoted(&format!("{}{}", elapsed_arg, args));
+                    let mut render = if opts.rprompt {
+                        format!(
+                            r#"{{
+    IFS= read -r -d '' {p}_left
+    IFS= read -r -d '' {p}_right
+}} < <({sprompt} prompt -e "${p}_status" -s zsh{args} --with-rprompt)"#,
+                            p = p,
+                            sprompt = sprompt,
+                            args = args
+                        )
+                    } else {
+                        format!(
+                            r#"{p}_left="$({sprompt} prompt -e "${p}_status" -s zsh{args})""#,
+                            p = p,
+                            sprompt = sprompt,
+                            args = args
+                        )
+                    };
+                    if opts.cache_on_cwd {
+                        render = format!(
+                            r#"if [ "${p}_ran" != false ] || [ "${p}_status" != 0 ] ||
+    [ "$PWD" != "${p}_last_pwd" ]; then
+    {p}_last_pwd="$PWD"
+{render}
+fi
+{p}_ran=false"#,
+                            p = p,
+                            render = indent(&render)
+                        );
+                    }
+                    format!(
+                        r#"{p}_prompt() {{
+    local {p}_status="$?"
+{render}
 }}
+autoload -Uz add-zsh-hook
+add-zsh-hook precmd {p}_prompt
+PROMPT='${{{p}_left}}'{rprompt}"#,
+                        p = p,
+                        render = indent(&render),
+                        rprompt = if opts.rprompt {
+                            format!("\nRPROMPT='${{{}_right}}'", p)
+                        } else {
+                            String::new()
+                        }
+                    )
+                } else {
+                    format!(
+                        r#"PROMPT="\$({} prompt -e "\$?" -s zsh{}{})""#,
+                        sprompt, elapsed_arg, args
+                    )
+                };
+                format!(
+                    r#"
+{hooks}
 setopt PROMPT_SUBST
-PROMPT="\$(sprompt prompt -e "\$?" -s zsh --elapsed-seconds "\$(( SECONDS - _sprompt_last_seconds ))"{args})"
+{prompt}
+"#,
+                    hooks = hooks,
+                    prompt = prompt,
+                )
+                .trim()
+                .into()
+            }
+            Self::Bash if timing => {
+                // `EPOCHREALTIME` has microseconds, with the decimal separator of the locale.
+                let (start, elapsed, elapsed_arg) = if opts.precise_timing {
+                    (
+                        format!(r#"{}_start_ns="${{EPOCHREALTIME/[.,]/}}000""#, p),
+                        // sprompt computes the elapsed time from the start.
+                        String::new(),
+                        format!(r#"--start-ns "${}_start_ns""#, p),
+                    )
+                } else {
+                    (
+                        format!(r#"{}_start_seconds="$SECONDS""#, p),
+                        format!(
+                            r#"
+    {p}_elapsed_seconds="$(( SECONDS - {p}_start_seconds ))""#,
+                            p = p
+                        ),
+                        format!(r#"--elapsed-seconds "${}_elapsed_seconds""#, p),
+                    )
+                };
+                // `_sprompt_at_prompt` tracks where the shell is:
+                // - unset: before the first prompt, nothing ran yet.
+                // - true: the prompt was drawn, the user may be idle for any amount of time.
+                // - false: a command line is running, the timer started with its first
+                //   command.
+                // The DEBUG trap runs before every simple command, only the first one while
+                // at the prompt moves to false and starts the timer. The rest of the command
+                // line, tab completion functions (`COMP_LINE` is set) and `PROMPT_COMMAND`
+                // itself are ignored, so idle time never counts. `PROMPT_COMMAND` moves back
+                // to true. If it finds anything but false nothing ran, e.g. enter was pressed
+                // on an empty line, so the timer starts there and the elapsed time is 0.
+                let render = format!(
+                    r#"{} prompt -e "$STATUS" -s bash {}{}"#,
+                    sprompt, elapsed_arg, args
+                );
+                let prompt = if opts.cache_on_cwd {
+                    // Kept in a variable between prompts, `_sprompt_ps1` already is.
+                    let (start, end, print) = if ps1 {
+                        (bash_prompt_start, bash_prompt_end, String::new())
+                    } else {
+                        (
+                            format!(r#"{}_prompt="$("#, p),
+                            ")\"",
+                            format!(
+                                r#"
+    printf '%s' "${}_prompt""#,
+                                p
+                            ),
+                        )
+                    };
+                    format!(
+                        r#"if [ "${p}_at_prompt" = false ] || [ "$STATUS" != 0 ] ||
+        [ "$PWD" != "${p}_last_pwd" ]; then
+        {p}_last_pwd="$PWD"
+        {start}{render}{end}
+    fi
+    {p}_at_prompt=true{print}"#,
+                        p = p,
+                        start = start,
+                        render = render,
+                        end = end,
+                        print = print
+                    )
+                } else {
+                    format!(
+                        "{}_at_prompt=true\n    {}{}{}",
+                        p, bash_prompt_start, render, bash_prompt_end
+                    )
+                };
+                format!(
+                    r#"
+{p}_beforecmd() {{
+    if [ "${p}_at_prompt" = true ] && [ -z "$COMP_LINE" ] &&
+        [ "$BASH_COMMAND" != {p}_aftercmd ]; then
+        {p}_at_prompt=false
+        {start}
+    fi
+}}
+trap {p}_beforecmd DEBUG
+
+{p}_aftercmd() {{
+    STATUS="$?"
+    if [ "${p}_at_prompt" != false ]; then
+        {start}
+    fi{elapsed}
+    {prompt}
+}}
+PS1={ps1}
+PROMPT_COMMAND={p}_aftercmd
 "#,
-                args = args
+                    p = p,
+                    start = start,
+                    elapsed = elapsed,
+                    prompt = prompt,
+                    ps1 = bash_ps1
+                )
+                .trim()
+                .into()
+            }
+            Self::Bash => format!(
+                r#"
+{p}_aftercmd() {{
+    {prompt_start}{sprompt} prompt -e "$?" -s bash{args}{prompt_end}
+}}
+PS1={ps1}
+PROMPT_COMMAND={p}_aftercmd
+"#,
+                p = p,
+                sprompt = sprompt,
+                args = args,
+                prompt_start = bash_prompt_start,
+                prompt_end = bash_prompt_end,
+                ps1 = bash_ps1
             )
             .trim()
             .into(),
-            Self::Bash => format!(
+        };
+        let code = match &opts.cache {
+            // Print the prompt rendered after the previous command and render the next one in
+            // the background, detached from the output of the command substitution.
+            Some(cache) => format!(
                 r#"
-_sprompt_beforecmd() {{
-    [ "${{_sprompt_beforecmd_ran:=false}}" = true ] && return
-    _sprompt_beforecmd_ran=true
-    _sprompt_last_seconds="$SECONDS"
+{p}_cached() {{
+    [ -f {cache} ] || "$@" --write-cache {cache}
+    cat {cache}
+    ("$@" --write-cache {cache} </dev/null >/dev/null 2>&1 &)
 }}
-trap _sprompt_beforecmd DEBUG
-
-_sprompt_aftercmd() {{
-    STATUS="$?"
-    if [ "${{_sprompt_aftercmd_first_run:=true}}" = true ]; then
-        _sprompt_aftercmd_first_run=false
-    else
-        _sprompt_beforecmd_ran=false
-        if [ "$_sprompt_last_seconds" -eq 0 ]; then
-            _sprompt_last_seconds="$SECONDS"
-        fi
-        _sprompt_elapsed_seconds="$(( SECONDS - _sprompt_last_seconds ))"
-    fi
-    sprompt prompt -e "$STATUS" -s bash --elapsed-seconds "${{_sprompt_elapsed_seconds:=0}}"{args}
-}}
-# If PS1 is completely empty, pressing the <enter> key doesn't work.
-PS1=\ 
-PROMPT_COMMAND=_sprompt_aftercmd
+{code}
 "#,
-                args = args
+                p = p,
+                cache = Self::Bash.quote(cache),
+                code = code
             )
             .trim()
             .into(),
+            None => code,
+        };
+        // Added after the cache wrapper, the continuation prompt is never cached. In bash it's
+        // rendered once, zsh renders it like `PROMPT`.
+        match self {
+            Self::Zsh => format!(
+                r#"{}
+PROMPT2="\$(sprompt prompt --continuation -s zsh{})""#,
+                code, opts.continuation_args
+            ),
+            Self::Bash => format!(
+                r#"{}
+PS2="$(sprompt prompt --continuation -s bash{})""#,
+                code, opts.continuation_args
+            ),
+        }
+    }
+
+    /// Code that undoes `init_code` in the current session, restoring a default prompt.
+    /// `function_name` is the prefix passed to `init`.
+    fn uninstall_code(&self, function_name: &str) -> String {
+        match self {
+            Self::Zsh => format!(
+                r#"
+autoload -Uz add-zsh-hook
+add-zsh-hook -d preexec {p}_preexec
+add-zsh-hook -d precmd {p}_precmd
+add-zsh-hook -d precmd {p}_prompt
+unfunction -m '{p}_*'
+unset -m '{p}_*'
+PROMPT='%n@%m %1~ %# '
+PROMPT2='%_> '
+RPROMPT=''
+"#,
+                p = function_name
+            ),
+            Self::Bash => format!(
+                r#"
+trap - DEBUG
+unset -f $(compgen -A function {p}_)
+unset $(compgen -v {p}_) STATUS
+unset PROMPT_COMMAND
+PS1='\$ '
+PS2='> '
+"#,
+                p = function_name
+            ),
+        }
+        .trim()
+        .into()
+    }
+}
+
+#[test]
+fn test_uninstall_code() {
+    let bash = Shell::Bash.uninstall_code("_sprompt");
+    assert!(bash.contains("trap - DEBUG"));
+    assert!(bash.contains("unset -f $(compgen -A function _sprompt_)"));
+    assert!(bash.contains("unset PROMPT_COMMAND"));
+    assert!(bash.ends_with(
+        r"PS1='\$ '
+PS2='> '"
+    ));
+    let zsh = Shell::Zsh.uninstall_code("my_prompt");
+    assert!(zsh.contains("add-zsh-hook -d precmd my_prompt_precmd"));
+    assert!(zsh.contains("unfunction -m 'my_prompt_*'"));
+    assert!(!zsh.contains("_sprompt"));
+}
+
+#[test]
+fn test_shell_quote() {
+    assert_eq!("'a b'", Shell::Bash.quote("a b"));
+    assert_eq!(r"'it'\''s'", Shell::Bash.quote("it's"));
+    assert_eq!(r#"'\$HOME \"x\"'"#, Shell::Zsh.quote(r#"$HOME "x""#));
+}
+
+#[test]
+fn test_init_code() {
+    let zsh = Shell::Zsh.init_code(" -u", &InitOptions::default());
+    assert!(zsh.contains("preexec()"));
+    assert!(zsh.contains("precmd()"));
+    assert!(zsh.contains("setopt PROMPT_SUBST"));
+    assert!(zsh.contains(" -u)\""));
+
+    let rprompt = InitOptions {
+        rprompt: true,
+        continuation_args: " -u".into(),
+        ..Default::default()
+    };
+    assert!(Shell::Zsh.init_code(" -u", &rprompt).contains(
+        r#"(sprompt prompt -e "$_sprompt_status" -s zsh --elapsed-seconds "$(( SECONDS"#
+    ));
+    assert!(Shell::Zsh.init_code(" -u", &rprompt).ends_with(
+        r#"RPROMPT='${_sprompt_right}'
+PROMPT2="\$(sprompt prompt --continuation -s zsh -u)""#
+    ));
+
+    let cache_on_cwd = InitOptions {
+        cache_on_cwd: true,
+        ..Default::default()
+    };
+    let zsh = Shell::Zsh.init_code(" -u", &cache_on_cwd);
+    assert!(zsh.contains("    _sprompt_ran=true\n"));
+    assert!(zsh.contains(r#"[ "$PWD" != "$_sprompt_last_pwd" ]; then"#));
+    assert!(zsh.contains("PROMPT='${_sprompt_left}'\n"));
+    let bash = Shell::Bash.init_code(" -u", &cache_on_cwd);
+    assert!(bash.contains(r#"_sprompt_prompt="$(sprompt prompt -e "$STATUS" -s bash "#));
+    assert!(bash.contains(r#"printf '%s' "$_sprompt_prompt""#));
+
+    // Without the trailing space readline has no visible character to draw again.
+    let no_trailing_space = InitOptions {
+        trailing_space: false,
+        ..Default::default()
+    };
+    let bash = Shell::Bash.init_code(" --no-trailing-space", &no_trailing_space);
+    assert!(bash.contains(
+        r#"_sprompt_ps1="$(sprompt prompt -e "$STATUS" -s bash --elapsed-seconds "$_sprompt_elapsed_seconds" --no-trailing-space --ps1-escapes)""#
+    ));
+    assert!(bash.contains("\nPS1='${_sprompt_ps1}'\n"));
+    let bash = Shell::Bash.init_code(" -u", &InitOptions::default());
+    assert!(bash.contains("\n    sprompt prompt -e"));
+    assert!(bash.contains("\nPS1='\\[\\e[1D\\] '\n"));
+    let zsh = Shell::Zsh.init_code(" --no-trailing-space", &no_trailing_space);
+    assert!(zsh.contains(r#"-s zsh --elapsed-seconds "\$(( SECONDS - _sprompt_last_seconds ))" --no-trailing-space)""#));
+    assert!(!zsh.contains("PS1"));
+
+    let bash = Shell::Bash.init_code(" -u -p", &InitOptions::default());
+    assert!(bash.contains("_sprompt_beforecmd()"));
+    assert!(bash.contains("_sprompt_aftercmd()"));
+    assert!(bash.contains("trap _sprompt_beforecmd DEBUG"));
+    assert!(bash.contains("PROMPT_COMMAND=_sprompt_aftercmd"));
+    assert!(bash.contains(" -u -p\n"));
+    assert!(bash.ends_with(r#"PS2="$(sprompt prompt --continuation -s bash)""#));
+
+    let opts = InitOptions {
+        timing: false,
+        ..Default::default()
+    };
+    for shell in &[Shell::Zsh, Shell::Bash] {
+        let named = InitOptions {
+            function_name: "my_prompt".into(),
+            ..Default::default()
+        };
+        let code = shell.init_code("", &named);
+        assert!(code.contains("my_prompt_"));
+        assert!(!code.contains("_sprompt_"));
+        // Arguments aren't renamed, even when they look like one of the names.
+        let code = shell.init_code(
+            &format!(" --word-on {}", shell.quote("_sprompt_x sprompt prompt")),
+            &InitOptions {
+                cache: Some("/tmp/_sprompt_cache".into()),
+                ..named
+            },
+        );
+        assert!(code.contains("_sprompt_x sprompt prompt"));
+        assert!(code.contains("/tmp/_sprompt_cache"));
+        assert!(code.contains("my_prompt_cached sprompt prompt"));
+        for code in &[
+            shell.init_code("", &InitOptions::default()),
+            shell.init_code(" -u -p", &InitOptions::default()),
+            shell.init_code(" -u", &opts),
+        ] {
+            let line = code.lines().find(|x| x.contains("sprompt prompt")).unwrap();
+            assert!(!line.trim().contains("  "), "{:?}", line);
+        }
+        assert!(!shell.init_code("", &opts).contains("--elapsed-seconds"));
+
+        let precise = InitOptions {
+            precise_timing: true,
+            ..Default::default()
+        };
+        let code = shell.init_code("", &precise);
+        assert!(code.contains("EPOCHREALTIME"));
+        assert!(code.contains("--start-ns"));
+        assert!(!code.contains("--elapsed-seconds"));
+
+        let cached = InitOptions {
+            cache: Some("/tmp/my prompt".into()),
+            ..Default::default()
+        };
+        let code = shell.init_code("", &cached);
+        assert!(code.contains("_sprompt_cached sprompt prompt"));
+        assert!(code.contains("--write-cache '/tmp/my prompt'"));
+    }
+
+    for opts in &[
+        InitOptions {
+            ps1: true,
+            ..Default::default()
+        },
+        InitOptions {
+            ps1: true,
+            timing: false,
+            ..Default::default()
+        },
+    ] {
+        let bash = Shell::Bash.init_code(" -u", opts);
+        assert!(bash.contains("PS1='${_sprompt_ps1}'\n"));
+        assert!(bash.contains(r#"_sprompt_ps1="$(sprompt prompt -e "#));
+        assert!(bash.contains(" -u --ps1-escapes)\"\n"));
+    }
+}
+
+/// Indent every line of `code` by four spaces.
+fn indent(code: &str) -> String {
+    code.lines()
+        .map(|x| format!("    {}", x))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Undo the escaping needed inside a double quoted string, e.g. `\$COLUMNS` to `$COLUMNS`.
+fn unescape_double_quoted(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some('\\' | '$' | '"' | '`') if c == '\\' => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
         }
     }
+    unescaped
+}
+
+#[test]
+fn test_unescape_double_quoted() {
+    assert_eq!(
+        r#" --max-width "$COLUMNS" --word-on 'a'\''b$x'"#,
+        unescape_double_quoted(&format!(
+            r#" --max-width {} --word-on {}"#,
+            Shell::Zsh.var("COLUMNS"),
+            Shell::Zsh.quote("a'b$x")
+        ))
+    );
+    assert_eq!(r"\n", unescape_double_quoted(r"\n"));
 }
 
 impl TryFrom<&str> for Shell {
@@ -417,6 +6664,7 @@ impl TryFrom<&str> for Shell {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum Color {
     Black,
     Red,
@@ -426,12 +6674,27 @@ enum Color {
     Magenta,
     Cyan,
     White,
+    /// Truecolor, for terminals that support it.
+    Rgb(u8, u8, u8),
 }
 
 impl Color {
     // TODO(agnipau): Windows support.
-    const fn to_str(&self, bright: bool, shell: &Shell) -> &str {
-        match self {
+    fn to_str(self, bright: bool, shell: &Shell) -> Cow<'static, str> {
+        if let Self::Rgb(r, g, b) = self {
+            let code = format!(
+                "\u{001b}[38;2;{};{};{}{}m",
+                r,
+                g,
+                b,
+                if bright { ";1" } else { "" }
+            );
+            return match shell {
+                Shell::Bash => code.into(),
+                Shell::Zsh => format!("%{{{}%}}", code).into(),
+            };
+        }
+        Cow::Borrowed(match self {
             Self::Black => match shell {
                 Shell::Bash => {
                     if bright {
@@ -560,10 +6823,132 @@ impl Color {
                     }
                 }
             },
+            Self::Rgb(..) => unreachable!(),
+        })
+    }
+
+    /// Like `to_str`, for the background. sprompt never sets it, it's only shown by
+    /// `sprompt dump-escapes`.
+    fn to_bg_str(self, bright: bool, shell: &Shell) -> String {
+        let color = match self {
+            Self::Black => "40".into(),
+            Self::Red => "41".into(),
+            Self::Green => "42".into(),
+            Self::Yellow => "43".into(),
+            Self::Blue => "44".into(),
+            Self::Magenta => "45".into(),
+            Self::Cyan => "46".into(),
+            Self::White => "47".into(),
+            Self::Rgb(r, g, b) => format!("48;2;{};{};{}", r, g, b),
+        };
+        let code = format!("\u{001b}[{}{}m", color, if bright { ";1" } else { "" });
+        match shell {
+            Shell::Bash => code,
+            Shell::Zsh => format!("%{{{}%}}", code),
         }
     }
 }
 
+#[test]
+fn test_color_bright() {
+    assert_eq!("\u{001b}[36m", Color::Cyan.to_str(false, &Shell::Bash));
+    assert_eq!("\u{001b}[36;1m", Color::Cyan.to_str(true, &Shell::Bash));
+    assert_eq!(
+        "%{\u{001b}[35;1m%}",
+        Color::Magenta.to_str(true, &Shell::Zsh)
+    );
+    assert_eq!(
+        "\u{001b}[38;2;255;136;0m",
+        Color::Rgb(255, 136, 0).to_str(false, &Shell::Bash)
+    );
+    assert_eq!(
+        "%{\u{001b}[38;2;0;0;0;1m%}",
+        Color::Rgb(0, 0, 0).to_str(true, &Shell::Zsh)
+    );
+}
+
+/// Every color, normal and bright, foreground and background, and every attribute with its
+/// escape sequence for `shell`, see `sprompt dump-escapes`. One tab separated line each: the
+/// name, the sequence with ESC shown as `\e`, and its bytes in hex. Stable, so that it can be
+/// saved as a golden file.
+fn dump_escapes(shell: &Shell) -> String {
+    let colors = [
+        ("black", Color::Black),
+        ("red", Color::Red),
+        ("green", Color::Green),
+        ("yellow", Color::Yellow),
+        ("blue", Color::Blue),
+        ("magenta", Color::Magenta),
+        ("cyan", Color::Cyan),
+        ("white", Color::White),
+        ("rgb(255,136,0)", Color::Rgb(255, 136, 0)),
+    ];
+    let attributes = [
+        ("reset", Attribute::Reset),
+        ("bold", Attribute::Bold),
+        ("underline", Attribute::Underline),
+        ("reversed", Attribute::Reversed),
+    ];
+    let escapes = colors
+        .iter()
+        .flat_map(|(name, color)| {
+            [
+                (name.to_string(), color.to_str(false, shell)),
+                (format!("bright-{}", name), color.to_str(true, shell)),
+                (format!("bg-{}", name), color.to_bg_str(false, shell).into()),
+                (
+                    format!("bg-bright-{}", name),
+                    color.to_bg_str(true, shell).into(),
+                ),
+            ]
+        })
+        .chain(
+            attributes
+                .iter()
+                .map(|(name, attribute)| (name.to_string(), attribute.to_str(shell).into())),
+        );
+    let mut dump = String::new();
+    for (name, escape) in escapes {
+        let bytes = escape
+            .bytes()
+            .map(|x| format!("{:02x}", x))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let _ = writeln!(
+            &mut dump,
+            "{}\t{}\t{}",
+            name,
+            escape.replace('\u{001b}', r"\e"),
+            bytes
+        );
+    }
+    dump
+}
+
+#[test]
+fn test_dump_escapes() {
+    let bash = dump_escapes(&Shell::Bash);
+    assert_eq!(40, bash.lines().count());
+    assert!(bash
+        .lines()
+        .any(|x| x == r"bright-red	\e[31;1m	1b 5b 33 31 3b 31 6d"));
+    assert!(bash.lines().any(|x| x == r"bg-blue	\e[44m	1b 5b 34 34 6d"));
+    assert!(bash
+        .lines()
+        .any(|x| x == r"bg-bright-white	\e[47;1m	1b 5b 34 37 3b 31 6d"));
+    assert!(bash
+        .lines()
+        .any(|x| x.starts_with(r"bg-rgb(255,136,0)	\e[48;2;255;136;0m	")));
+    assert!(bash.ends_with("reversed\t\\e[7m\t1b 5b 37 6d\n"));
+    let zsh = dump_escapes(&Shell::Zsh);
+    assert!(zsh
+        .lines()
+        .any(|x| x.starts_with(r"bold	%{\e[1m%}	25 7b 1b")));
+    assert!(zsh
+        .lines()
+        .any(|x| x.starts_with(r"bg-bright-cyan	%{\e[46;1m%}	25 7b 1b")));
+}
+
 enum Attribute {
     Reset,
     Bold,
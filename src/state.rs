@@ -0,0 +1,61 @@
+//! Small pieces of state kept between prompts. sprompt runs once per prompt, so anything that
+//! depends on previous prompts is stored in files, one per shell session.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// `$XDG_STATE_HOME/sprompt`, defaulting to `~/.local/state/sprompt`.
+fn dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_STATE_HOME").filter(|x| !x.is_empty()) {
+        Some(x) => PathBuf::from(x),
+        None => {
+            let mut home = PathBuf::from(env::var_os("HOME")?);
+            home.push(".local/state");
+            home
+        }
+    };
+    Some(base.join("sprompt"))
+}
+
+/// Identifies the shell session, `$SPROMPT_SESSION` if set, otherwise the PID of the shell
+/// (sprompt is either run directly by the shell or `exec`d by the subshell of a command
+/// substitution).
+fn session_id() -> String {
+    env::var("SPROMPT_SESSION")
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| unsafe { libc::getppid() }.to_string())
+}
+
+/// A value persisted for the current shell session, or for all of them.
+pub struct State {
+    path: PathBuf,
+}
+
+impl State {
+    pub fn new(name: &str) -> Option<Self> {
+        let mut path = dir()?;
+        path.push(format!("{}-{}", session_id(), name));
+        Some(Self { path })
+    }
+
+    /// A value shared by all the shell sessions.
+    pub fn global(name: &str) -> Option<Self> {
+        let mut path = dir()?;
+        path.push(name);
+        Some(Self { path })
+    }
+
+    pub fn read(&self) -> Option<String> {
+        fs::read_to_string(&self.path).ok()
+    }
+
+    pub fn write(&self, contents: &str) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, contents)
+    }
+}
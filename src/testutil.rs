@@ -0,0 +1,57 @@
+//! Helpers to build throwaway git repositories in tests.
+
+use git2::{BranchType, Oid, Repository, Signature};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// An empty repository in a temporary directory, removed when the `TempDir` is dropped.
+pub fn temp_repo() -> (TempDir, Repository) {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = Repository::init(dir.path()).unwrap();
+    (dir, repo)
+}
+
+/// Write `contents` to `path` (relative to the workdir) and commit it on top of HEAD.
+pub fn commit(repo: &Repository, path: &str, contents: &str) -> Oid {
+    let workdir = repo.workdir().unwrap();
+    fs::write(workdir.join(path), contents).unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new(path)).unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    // Not `repo.signature()`, the machine running the tests may have no git identity.
+    let signature = Signature::now("sprompt", "sprompt@example.com").unwrap();
+    let parent = repo.head().ok().and_then(|x| x.peel_to_commit().ok());
+    let parents = parent.iter().collect::<Vec<_>>();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        &format!("Update {}", path),
+        &tree,
+        &parents,
+    )
+    .unwrap()
+}
+
+/// Create the branch `name` at HEAD and check it out.
+pub fn checkout_new_branch(repo: &Repository, name: &str) {
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    repo.branch(name, &head, false).unwrap();
+    repo.set_head(&format!("refs/heads/{}", name)).unwrap();
+}
+
+/// Make `upstream`, a local branch, the upstream of `branch`.
+pub fn set_upstream(repo: &Repository, branch: &str, upstream: &str) {
+    repo.find_branch(branch, BranchType::Local)
+        .unwrap()
+        .set_upstream(Some(upstream))
+        .unwrap();
+}
+
+/// Point HEAD directly to the current commit.
+pub fn detach(repo: &Repository) {
+    let oid = repo.head().unwrap().target().unwrap();
+    repo.set_head_detached(oid).unwrap();
+}
@@ -0,0 +1,256 @@
+use crate::{Color, Shell};
+use std::borrow::Cow;
+
+/// A color and whether to use its bright variant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThemeColor {
+    pub color: Color,
+    pub bright: bool,
+}
+
+impl ThemeColor {
+    pub const fn normal(color: Color) -> Self {
+        Self {
+            color,
+            bright: false,
+        }
+    }
+
+    pub const fn bright(color: Color) -> Self {
+        Self {
+            color,
+            bright: true,
+        }
+    }
+
+    pub fn to_str(self, shell: &Shell) -> Cow<'static, str> {
+        self.color.to_str(self.bright, shell)
+    }
+}
+
+/// The colors of the segments, by role rather than by segment so that related segments stay
+/// consistent.
+#[derive(Clone, Copy)]
+pub struct Theme {
+    pub name: &'static str,
+    pub root: ThemeColor,
+    pub path: ThemeColor,
+    pub path_readonly: ThemeColor,
+    pub path_outside_repo: ThemeColor,
+    /// The part of the path outside the repo with `--dim-outside-repo`.
+    pub dim: ThemeColor,
+    pub branch: ThemeColor,
+    pub signed: ThemeColor,
+    /// Overrides the colors of the toolchains, if set.
+    pub toolchain: Option<ThemeColor>,
+    /// Commits of the upstream not merged yet.
+    pub incoming: ThemeColor,
+    pub environment: ThemeColor,
+    /// Things worth a look, e.g. a dirty worktree or a slow command.
+    pub warning: ThemeColor,
+    pub error: ThemeColor,
+    /// The separator after suspending a command, with `--show-suspended`.
+    pub suspended: ThemeColor,
+    /// Informative counters, e.g. the commit count.
+    pub info: ThemeColor,
+    pub success: ThemeColor,
+}
+
+impl Theme {
+    pub const DEFAULT: Self = Self {
+        name: "default",
+        root: ThemeColor::normal(Color::Red),
+        path: ThemeColor::normal(Color::Cyan),
+        path_readonly: ThemeColor::normal(Color::Red),
+        path_outside_repo: ThemeColor::normal(Color::Yellow),
+        dim: ThemeColor::bright(Color::Black),
+        branch: ThemeColor::normal(Color::Magenta),
+        signed: ThemeColor::normal(Color::Green),
+        toolchain: None,
+        incoming: ThemeColor::normal(Color::Cyan),
+        environment: ThemeColor::normal(Color::Blue),
+        warning: ThemeColor::normal(Color::Yellow),
+        error: ThemeColor::normal(Color::Red),
+        suspended: ThemeColor::normal(Color::Blue),
+        info: ThemeColor::normal(Color::White),
+        success: ThemeColor::normal(Color::Green),
+    };
+
+    /// Only white and gray, errors stand out by being bright.
+    pub const MONO: Self = Self {
+        name: "mono",
+        root: ThemeColor::bright(Color::White),
+        path: ThemeColor::bright(Color::White),
+        path_readonly: ThemeColor::normal(Color::White),
+        path_outside_repo: ThemeColor::normal(Color::White),
+        dim: ThemeColor::bright(Color::Black),
+        branch: ThemeColor::normal(Color::White),
+        signed: ThemeColor::normal(Color::White),
+        toolchain: Some(ThemeColor::normal(Color::White)),
+        incoming: ThemeColor::normal(Color::White),
+        environment: ThemeColor::normal(Color::White),
+        warning: ThemeColor::normal(Color::White),
+        error: ThemeColor::bright(Color::White),
+        suspended: ThemeColor::normal(Color::White),
+        info: ThemeColor::bright(Color::Black),
+        success: ThemeColor::normal(Color::White),
+    };
+
+    pub const PASTEL: Self = Self {
+        name: "pastel",
+        root: ThemeColor::bright(Color::Red),
+        path: ThemeColor::bright(Color::Cyan),
+        path_readonly: ThemeColor::bright(Color::Red),
+        path_outside_repo: ThemeColor::bright(Color::Yellow),
+        dim: ThemeColor::bright(Color::Black),
+        branch: ThemeColor::bright(Color::Magenta),
+        signed: ThemeColor::bright(Color::Green),
+        toolchain: None,
+        incoming: ThemeColor::bright(Color::Cyan),
+        environment: ThemeColor::bright(Color::Blue),
+        warning: ThemeColor::bright(Color::Yellow),
+        error: ThemeColor::bright(Color::Red),
+        suspended: ThemeColor::bright(Color::Blue),
+        info: ThemeColor::bright(Color::White),
+        success: ThemeColor::bright(Color::Green),
+    };
+
+    /// Cold blues and cyans, in the spirit of the Nord palette.
+    pub const NORD: Self = Self {
+        name: "nord",
+        root: ThemeColor::normal(Color::Red),
+        path: ThemeColor::bright(Color::Cyan),
+        path_readonly: ThemeColor::normal(Color::Red),
+        path_outside_repo: ThemeColor::normal(Color::Yellow),
+        dim: ThemeColor::bright(Color::Black),
+        branch: ThemeColor::normal(Color::Blue),
+        signed: ThemeColor::normal(Color::Cyan),
+        toolchain: Some(ThemeColor::normal(Color::Cyan)),
+        incoming: ThemeColor::bright(Color::Blue),
+        environment: ThemeColor::normal(Color::Blue),
+        warning: ThemeColor::normal(Color::Yellow),
+        error: ThemeColor::normal(Color::Red),
+        suspended: ThemeColor::normal(Color::Blue),
+        info: ThemeColor::bright(Color::Black),
+        success: ThemeColor::normal(Color::Cyan),
+    };
+
+    pub const ALL: [Self; 4] = [Self::DEFAULT, Self::MONO, Self::PASTEL, Self::NORD];
+
+    pub const SUPPORTED: [&'static str; 4] = ["default", "mono", "pastel", "nord"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|x| x.name == name).copied()
+    }
+
+    /// The names of the colors that can be set with `set`, e.g. in the config file.
+    pub const ROLES: [&'static str; 15] = [
+        "root",
+        "path",
+        "path_readonly",
+        "path_outside_repo",
+        "dim",
+        "branch",
+        "signed",
+        "toolchain",
+        "incoming",
+        "environment",
+        "warning",
+        "error",
+        "suspended",
+        "info",
+        "success",
+    ];
+
+    /// Replace the color of `role`, one of `ROLES`.
+    pub fn set(&mut self, role: &str, color: ThemeColor) -> Result<(), String> {
+        match role {
+            "root" => self.root = color,
+            "path" => self.path = color,
+            "path_readonly" => self.path_readonly = color,
+            "path_outside_repo" => self.path_outside_repo = color,
+            "dim" => self.dim = color,
+            "branch" => self.branch = color,
+            "signed" => self.signed = color,
+            "toolchain" => self.toolchain = Some(color),
+            "incoming" => self.incoming = color,
+            "environment" => self.environment = color,
+            "warning" => self.warning = color,
+            "error" => self.error = color,
+            "suspended" => self.suspended = color,
+            "info" => self.info = color,
+            "success" => self.success = color,
+            _ => return Err(format!("unknown color `{}`", role)),
+        }
+        Ok(())
+    }
+}
+
+/// Parse a color name like `red` or `bright-cyan`, or a truecolor one like `#ff8800`.
+pub fn parse_color(s: &str) -> Result<ThemeColor, String> {
+    let invalid = || format!("invalid color `{}`", s);
+    if let Some(hex) = s.strip_prefix('#') {
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        return Ok(ThemeColor::normal(Color::Rgb(
+            channel(0),
+            channel(2),
+            channel(4),
+        )));
+    }
+    let (bright, name) = match s.strip_prefix("bright-") {
+        Some(x) => (true, x),
+        None => (false, s),
+    };
+    let color = match name {
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "white" => Color::White,
+        _ => return Err(invalid()),
+    };
+    Ok(ThemeColor { color, bright })
+}
+
+#[test]
+fn test_parse_color() {
+    assert_eq!(
+        Ok(ThemeColor::normal(Color::Rgb(0xff, 0x88, 0x00))),
+        parse_color("#ff8800")
+    );
+    assert_eq!(Ok(ThemeColor::normal(Color::Red)), parse_color("red"));
+    assert_eq!(
+        Ok(ThemeColor::bright(Color::Cyan)),
+        parse_color("bright-cyan")
+    );
+    assert_eq!(Err("invalid color `purple`".into()), parse_color("purple"));
+    assert!(parse_color("#ff88").is_err());
+    assert!(parse_color("#gg8800").is_err());
+    assert!(parse_color("bright-#ff8800").is_err());
+}
+
+#[test]
+fn test_theme_set() {
+    let mut theme = Theme::DEFAULT;
+    for role in Theme::ROLES.iter() {
+        assert!(theme.set(role, ThemeColor::normal(Color::White)).is_ok());
+    }
+    assert_eq!(Some(ThemeColor::normal(Color::White)), theme.toolchain);
+    assert!(theme
+        .set("background", ThemeColor::normal(Color::White))
+        .is_err());
+}
+
+#[test]
+fn test_theme_names() {
+    for name in Theme::SUPPORTED.iter() {
+        assert_eq!(Some(*name), Theme::from_name(name).map(|x| x.name));
+    }
+    assert!(Theme::from_name("solarized").is_none());
+}
@@ -0,0 +1,146 @@
+use crate::Color;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Return the first ancestor of `start` (itself included) that contains one of `names`,
+/// together with the name that was found.
+pub fn find_up<'a>(start: &Path, names: &[&'a str]) -> Option<(PathBuf, &'a str)> {
+    start.ancestors().find_map(|dir| {
+        names
+            .iter()
+            .find(|name| dir.join(name).is_file())
+            .map(|name| (dir.join(name), *name))
+    })
+}
+
+/// A language toolchain whose version is shown when inside one of its projects.
+#[derive(Clone, Copy)]
+pub enum Toolchain {
+    Node,
+    Rust,
+}
+
+impl Toolchain {
+    pub const ALL: [Self; 2] = [Self::Node, Self::Rust];
+
+    /// Files marking the root of a project using this toolchain.
+    const fn project_files(&self) -> &[&str] {
+        match self {
+            Self::Node => &["package.json"],
+            Self::Rust => &["Cargo.toml"],
+        }
+    }
+
+    /// Files pinning the version of this toolchain, besides asdf's `.tool-versions`.
+    const fn version_files(&self) -> &[&str] {
+        match self {
+            Self::Node => &[".node-version", ".nvmrc"],
+            Self::Rust => &[],
+        }
+    }
+
+    /// Name of the toolchain in `.tool-versions`.
+    const fn asdf_name(&self) -> &str {
+        match self {
+            Self::Node => "nodejs",
+            Self::Rust => "rust",
+        }
+    }
+
+    const fn binary(&self) -> &str {
+        match self {
+            Self::Node => "node",
+            Self::Rust => "rustc",
+        }
+    }
+
+    pub const fn color(&self) -> Color {
+        match self {
+            Self::Node => Color::Green,
+            Self::Rust => Color::Red,
+        }
+    }
+
+    pub const fn symbol(&self, unicode: bool) -> &str {
+        match self {
+            Self::Node => {
+                if unicode {
+                    "⬢ "
+                } else {
+                    "node "
+                }
+            }
+            Self::Rust => {
+                if unicode {
+                    "🦀 "
+                } else {
+                    "rust "
+                }
+            }
+        }
+    }
+
+    /// Version of the toolchain used in `cwd`, `None` if `cwd` isn't inside one of its
+    /// projects. Version managers like asdf or fnm pin versions in files, reading them is much
+    /// faster than running the binary, which is only done when no file is found.
+    pub fn version(&self, cwd: &Path) -> Option<String> {
+        find_up(cwd, self.project_files())?;
+        self.pinned_version(cwd).or_else(|| self.binary_version())
+    }
+
+    fn pinned_version(&self, cwd: &Path) -> Option<String> {
+        let mut names = self.version_files().to_vec();
+        names.push(".tool-versions");
+        let (path, name) = find_up(cwd, &names)?;
+        let contents = fs::read_to_string(path).ok()?;
+        if name == ".tool-versions" {
+            parse_tool_versions(&contents, self.asdf_name())
+        } else {
+            let version = contents.lines().next()?.trim();
+            if version.is_empty() {
+                None
+            } else {
+                Some(version.trim_start_matches('v').into())
+            }
+        }
+    }
+
+    fn binary_version(&self) -> Option<String> {
+        let output = Command::new(self.binary()).arg("--version").output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let stdout = String::from_utf8(output.stdout).ok()?;
+        // `v16.13.0` for node, `rustc 1.49.0 (e1884a8e3 2020-12-29)` for rust.
+        let version = match self {
+            Self::Node => stdout.trim(),
+            Self::Rust => stdout.split_whitespace().nth(1)?,
+        };
+        Some(version.trim_start_matches('v').into())
+    }
+}
+
+/// Version of `tool` in the contents of an asdf `.tool-versions` file.
+fn parse_tool_versions(contents: &str, tool: &str) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let line = line.split('#').next()?;
+        let mut words = line.split_whitespace();
+        if words.next()? == tool {
+            words.next().map(|x| x.into())
+        } else {
+            None
+        }
+    })
+}
+
+#[test]
+fn test_parse_tool_versions() {
+    let contents = "# comment\nnodejs 16.13.0 system\nrust 1.49.0 # pinned\n";
+    assert_eq!(
+        Some("16.13.0".into()),
+        parse_tool_versions(contents, "nodejs")
+    );
+    assert_eq!(Some("1.49.0".into()), parse_tool_versions(contents, "rust"));
+    assert_eq!(None, parse_tool_versions(contents, "python"));
+}
@@ -0,0 +1,93 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Type `lines` in an interactive bash using the init code with `flags`, waiting the given time
+/// before each line, and return the prompts that were printed. With `--ps1` the prompt is set
+/// as `PS1`, which bash prints to stderr. The user's config file and state are left out.
+fn prompts(flags: &[&str], lines: &[(u64, &str)]) -> Vec<String> {
+    let dir = tempfile::tempdir().unwrap();
+    let rc = dir.path().join("bashrc");
+    let config = dir.path().join("config.toml");
+    std::fs::write(&config, "").unwrap();
+    let ps1 = flags.contains(&"--ps1");
+    std::fs::write(
+        &rc,
+        format!(
+            "eval \"$(sprompt init -s bash --color never {})\"\n",
+            flags.join(" ")
+        ),
+    )
+    .unwrap();
+    let bin = Path::new(env!("CARGO_BIN_EXE_sprompt")).parent().unwrap();
+    let path = format!(
+        "{}:{}",
+        bin.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+    let mut bash = Command::new("bash")
+        .arg("--noprofile")
+        .arg("--rcfile")
+        .arg(&rc)
+        .arg("-i")
+        .current_dir(dir.path())
+        .env("PATH", path)
+        .env("SPROMPT_CONFIG", &config)
+        .env("XDG_STATE_HOME", dir.path())
+        .env_remove("PROMPT_COMMAND")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(if ps1 { Stdio::piped() } else { Stdio::null() })
+        .spawn()
+        .unwrap();
+    let mut stdin = bash.stdin.take().unwrap();
+    for (millis, line) in lines {
+        thread::sleep(Duration::from_millis(*millis));
+        writeln!(stdin, "{}", line).unwrap();
+    }
+    writeln!(stdin, "exit").unwrap();
+    let output = bash.wait_with_output().unwrap();
+    let stdout = String::from_utf8(if ps1 { output.stderr } else { output.stdout }).unwrap();
+    let mut prompts = stdout
+        .split("::")
+        .map(|x| x.trim().to_string())
+        .collect::<Vec<_>>();
+    // What follows the last separator, i.e. the trailing space.
+    prompts.pop();
+    prompts
+}
+
+#[test]
+fn test_bash_timing_ignores_idle_time() {
+    let prompts = prompts(&[], &[(200, "sleep 2"), (2500, ""), (2500, "true")]);
+    assert_eq!(4, prompts.len(), "{:?}", prompts);
+    // Before the first command.
+    assert!(!prompts[0].contains("took"), "{:?}", prompts);
+    assert!(prompts[1].ends_with("took 2s"), "{:?}", prompts);
+    // An empty line and a quick command after sitting at the prompt.
+    assert!(!prompts[2].contains("took"), "{:?}", prompts);
+    assert!(!prompts[3].contains("took"), "{:?}", prompts);
+}
+
+#[test]
+fn test_bash_ps1() {
+    let prompts = prompts(&["--ps1"], &[(200, "sleep 2"), (200, "true")]);
+    assert_eq!(3, prompts.len(), "{:?}", prompts);
+    assert!(!prompts[0].contains("took"), "{:?}", prompts);
+    assert!(prompts[1].ends_with("took 2s"), "{:?}", prompts);
+    assert!(!prompts[2].contains("took"), "{:?}", prompts);
+}
+
+#[test]
+fn test_bash_cache_on_cwd() {
+    for flags in &[&["--cache-on-cwd"][..], &["--cache-on-cwd", "--ps1"]] {
+        let prompts = prompts(flags, &[(200, "sleep 2"), (200, ""), (200, "cd /")]);
+        assert_eq!(4, prompts.len(), "{:?}", prompts);
+        assert!(prompts[1].ends_with("took 2s"), "{:?}", prompts);
+        // Nothing ran, the same prompt again.
+        assert!(prompts[2].ends_with("took 2s"), "{:?}", prompts);
+        assert!(!prompts[3].contains("took"), "{:?}", prompts);
+    }
+}
@@ -0,0 +1,47 @@
+use git2::Repository;
+use std::process::Command;
+
+/// Run `sprompt prompt` in a fresh repository on the `main` branch and return the output,
+/// stripped of the color escapes. The user's config file and state are left out.
+fn prompt(extra_args: &[&str]) -> String {
+    let dir = tempfile::tempdir().unwrap();
+    let state = tempfile::tempdir().unwrap();
+    let config = state.path().join("config.toml");
+    std::fs::write(&config, "").unwrap();
+    let repo = Repository::init(dir.path()).unwrap();
+    repo.set_head("refs/heads/main").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sprompt"))
+        .args(["prompt", "-s", "bash", "-e", "0", "--elapsed-seconds", "5"])
+        .args(extra_args)
+        .current_dir(dir.path())
+        .env("SPROMPT_CONFIG", &config)
+        .env("XDG_STATE_HOME", state.path())
+        .env("LANG", "C")
+        .env_remove("LC_ALL")
+        .env_remove("LC_MESSAGES")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let mut prompt = String::new();
+    let mut escape = false;
+    for c in String::from_utf8(output.stdout).unwrap().chars() {
+        match c {
+            '\x1b' => escape = true,
+            'm' if escape => escape = false,
+            c if !escape => prompt.push(c),
+            _ => {}
+        }
+    }
+    // Drop the root segment and the path, the latter depends on the temporary directory.
+    let start = prompt
+        .find(" on ")
+        .or_else(|| prompt.find(" main "))
+        .unwrap();
+    prompt[start..].into()
+}
+
+#[test]
+fn test_compact() {
+    assert_eq!(" on main took 5s :: ", prompt(&[]));
+    assert_eq!(" main 5s :: ", prompt(&["--compact"]));
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Run `sprompt <subcommand>` for a command exiting with `exit_code`, with the state kept in
+/// `state` for a single session, and return its output.
+fn run(state: &Path, subcommand: &str, exit_code: &str) -> String {
+    let config = state.join("config.toml");
+    std::fs::write(&config, "").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sprompt"))
+        .args([subcommand, "-s", "bash", "--color", "never", "--no-timing"])
+        .args(["-e", exit_code, "--sticky-errors", "--mood"])
+        .current_dir(state)
+        .env("XDG_STATE_HOME", state)
+        .env("SPROMPT_CONFIG", &config)
+        .env("SPROMPT_SESSION", "test")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_debug_reads_the_state() {
+    let dir = tempfile::tempdir().unwrap();
+    run(dir.path(), "prompt", "1");
+    run(dir.path(), "prompt", "1");
+    // Explains the next prompt, without drawing it.
+    let report = run(dir.path(), "debug", "1");
+    assert!(report.contains("sticky_error shown:"), "{}", report);
+    assert!(report.contains("mood         shown:"), "{}", report);
+    assert!(report.contains(":( "), "{}", report);
+    for _ in 0..2 {
+        let report = run(dir.path(), "debug", "0");
+        assert!(report.contains("sticky_error shown:"), "{}", report);
+        assert!(report.contains("mood         hidden:"), "{}", report);
+    }
+    // Still after the two failures, the debug runs didn't record anything.
+    let prompt = run(dir.path(), "prompt", "0");
+    assert!(prompt.contains(" x :: "), "{:?}", prompt);
+}